@@ -1,40 +1,190 @@
 //! Defines the read-only application state and hub for utility
 //! functions.
 
-use crate::client::{download, list_keys, upload};
+use crate::client::{
+    delete, download, head, list_keys, upload, upload_contents, MARKER_METADATA_KEY,
+};
 use crate::conf::Settings;
+use crate::error::{self, Error};
 use crate::jq;
-use crate::sign::{compute_signatures, empty_signatures, find_signature_differences};
+use crate::process;
+use crate::sign::{compute_signatures, empty_signatures, find_signature_differences, list_files};
 use anyhow::{anyhow, Context, Result};
 use aws_lambda_events::s3::S3EventRecord;
 use aws_sdk_s3::types::{Object, Owner};
 use aws_smithy_types_convert::date_time::DateTimeExt;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use envy::from_env;
 use once_cell::sync::OnceCell;
-use regex::Regex;
-use serde::Serialize;
+use percent_encoding::percent_decode_str;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
     cmp::max,
-    collections::{BTreeSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     env::args_os,
     ffi::OsString,
     fs,
     path::{Path, PathBuf},
+    process::{Command, ExitStatus},
 };
 use tempfile::TempDir;
-use tokio::{process::Command, task::JoinSet};
-use tracing::{info, instrument, warn};
+use tokio::{
+    task::JoinSet,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, info_span, instrument, warn, Instrument};
 
 /// A batch of S3 events that share a key prefix and represent objects
 /// that belong to the same bucket.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct EventBatch {
     pub bucket: String,
     pub prefix: String,
+
+    /// The named capture groups of `match_key`, as matched against
+    /// the key of the first event in the batch.
+    pub captures: BTreeMap<String, String>,
+
+    /// The time of the first event in the batch.
+    pub event_time: Option<DateTime<Utc>>,
+
+    /// The identifiers of the sources (e.g. SQS messages) that
+    /// contributed events to the batch.
+    pub sources: BTreeSet<String>,
+
+    /// The S3 event records that contributed to the batch.
+    pub records: Vec<S3EventRecord>,
+
+    /// Whether the batch is made of removal events, to be handled by
+    /// the removal handler command.
+    pub removal: bool,
 }
 
+impl EventBatch {
+    /// Merge the events of another batch sharing the same bucket and
+    /// prefix into this one.
+    pub fn merge(&mut self, other: EventBatch) {
+        if other.event_time < self.event_time {
+            self.event_time = other.event_time;
+            self.captures = other.captures;
+        }
+        self.sources.extend(other.sources);
+        self.records.extend(other.records);
+    }
+}
+
+/// The outcome of handling a batch of S3 event records, as
+/// determined by the execution filter and the exit status of the
+/// handler command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The handler command succeeded, and its outputs were uploaded.
+    Succeeded,
+
+    /// The execution filter prevented the handler command from
+    /// running.
+    Skipped,
+
+    /// The execution filter asked for the events to be handled again
+    /// after the given delay, without running the handler command.
+    Deferred(Duration),
+
+    /// The handler command failed, but could succeed if the events
+    /// are handled again.
+    RetryableFailure(ExitStatus),
+
+    /// The handler command failed, and the events shouldn't be
+    /// handled again.
+    PermanentFailure(ExitStatus),
+}
+
+impl Outcome {
+    /// Whether the events that led to this outcome should be handled
+    /// again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Outcome::RetryableFailure(_) | Outcome::Deferred(_))
+    }
+}
+
+/// A report of a permanent failure of the handler command.
+#[derive(Serialize)]
+struct FailureReport<'fields> {
+    bucket: &'fields str,
+    prefix: &'fields str,
+    command: Vec<String>,
+    exit_code: Option<i32>,
+    time: DateTime<Utc>,
+}
+
+/// Default `run` value of filter directives.
+fn default_run() -> bool {
+    true
+}
+
+/// The directives the execution filter may return as an object, to
+/// decide how a batch is handled.
+#[derive(Deserialize)]
+struct FilterDirectives {
+    /// Whether the handler command should run.
+    #[serde(default = "default_run")]
+    run: bool,
+
+    /// The amount of seconds to wait before handling the events
+    /// again, instead of running the handler command.
+    #[serde(default)]
+    defer: Option<u64>,
+
+    /// Additional environment variables for the handler command.
+    #[serde(default)]
+    env: BTreeMap<String, Value>,
+
+    /// The keys to pull, replacing the selection made by
+    /// `pull_match_keys`.
+    #[serde(default)]
+    pull: Option<BTreeSet<String>>,
+}
+
+impl Default for FilterDirectives {
+    fn default() -> Self {
+        FilterDirectives {
+            run: default_run(),
+            defer: None,
+            env: BTreeMap::new(),
+            pull: None,
+        }
+    }
+}
+
+impl FilterDirectives {
+    /// Interpret the result of the execution filter. Objects are read
+    /// as directives, `false` stops the execution, and any other
+    /// result lets the execution continue.
+    fn from_result(result: Option<Value>) -> Result<Self> {
+        match result {
+            Some(Value::Bool(false)) => Ok(FilterDirectives {
+                run: false,
+                ..Default::default()
+            }),
+            Some(result @ Value::Object(_)) => serde_json::from_value(result)
+                .context("Execution filter returned malformed directives"),
+            _ => Ok(Default::default()),
+        }
+    }
+}
+
+/// The global variables available to the execution filter, holding
+/// the bucket and prefix of the batch, its event records, and the
+/// named capture groups of `match_key`.
+const FILTER_VARIABLES: [&str; 4] = ["bucket", "prefix", "events", "captures"];
+
+/// The placeholders available to key templates, apart from the named
+/// capture groups of `match_key`.
+const TEMPLATE_BUILTINS: [&str; 8] = [
+    "bucket", "prefix", "year", "month", "day", "hour", "minute", "second",
+];
+
 /// An App is an initialized application state, derived from
 /// settings. This is only useful to pre-compute stuff that will be
 /// used constantly.
@@ -45,17 +195,38 @@ pub struct App {
     /// The regex that matches S3 event keys.
     pub match_key_re: Regex,
 
+    /// The regex that matches placeholders in key templates.
+    pub template_placeholder_re: Regex,
+
+    /// The regexes that match the names of events to be handled.
+    pub match_event_names_res: Vec<Regex>,
+
     /// The regexes that match files to be pulled.
     pub pull_match_key_res: Vec<Regex>,
 
     /// The execution filter expression to use on pulled objects.
     pub execution_filter: Option<jq::Filter>,
 
+    /// The capture groups of `match_key` referenced by the batch key
+    /// template, each of which must match for the template to apply.
+    pub batch_key_groups: Vec<String>,
+
+    /// The expression that derives the batch prefix from an event
+    /// record.
+    pub batch_key_filter: Option<jq::Filter>,
+
     /// The program that needs to be executed as the handler.
     pub handler_command_program: OsString,
 
     /// The arguments passed to the executed handler program.
     pub handler_command_args: VecDeque<OsString>,
+
+    /// The program that needs to be executed as the handler of
+    /// removal events, if any.
+    pub removal_handler_command_program: Option<OsString>,
+
+    /// The arguments passed to the executed removal handler program.
+    pub removal_handler_command_args: VecDeque<OsString>,
 }
 
 impl App {
@@ -74,6 +245,39 @@ impl App {
                 &settings.match_key
             )
         })?;
+        let template_placeholder_re = Regex::new(r"\{(\w*)\}")?;
+        let placeholders = TEMPLATE_BUILTINS
+            .iter()
+            .copied()
+            .chain(match_key_re.capture_names().flatten())
+            .collect::<BTreeSet<&str>>();
+        for (name, template) in [
+            ("Target prefix", &settings.target_prefix),
+            ("Failure report prefix", &settings.failure_report_prefix),
+            ("Handler log prefix", &settings.handler_log_prefix),
+        ] {
+            if let Some(template) = template {
+                for captures in template_placeholder_re.captures_iter(template) {
+                    if !placeholders.contains(&captures[1]) {
+                        return Err(anyhow!(
+                            "{} {:?} uses unknown placeholder {:?}",
+                            name,
+                            template,
+                            &captures[0]
+                        ));
+                    }
+                }
+            }
+        }
+        let mut match_event_names_res = Vec::with_capacity(settings.match_event_names.len());
+        for match_event_name in &settings.match_event_names {
+            match_event_names_res.push(glob_to_regex(match_event_name).with_context(|| {
+                format!(
+                    "Failed to build an event name matching regex from {:?}",
+                    &match_event_name
+                )
+            })?);
+        }
         let mut pull_match_key_res = Vec::with_capacity(max(settings.pull_match_keys.len(), 1));
         for pull_match_key in &settings.pull_match_keys {
             pull_match_key_res.push(Regex::new(pull_match_key).with_context(|| {
@@ -93,7 +297,7 @@ impl App {
         ) {
             (expr, filepath) if expr.is_empty() && filepath.is_empty() => Ok(None),
             (expr, filepath) if filepath.is_empty() => {
-                let f = jq::compile(expr).map_err(|e| {
+                let f = jq::compile(expr, &FILTER_VARIABLES).map_err(|e| {
                     anyhow!("Failed to compile execution filter expression: {:?}", e)
                 })?;
                 Ok(Some(f))
@@ -102,7 +306,7 @@ impl App {
                 let file_expr = fs::read_to_string(filepath).with_context(|| {
                     format!("Failed to read execution filter file: {:?}", filepath)
                 })?;
-                let f = jq::compile(&file_expr).map_err(|e| {
+                let f = jq::compile(&file_expr, &FILTER_VARIABLES).map_err(|e| {
                     anyhow!(
                         "Failed to compile execution filter expression within file: {:?}",
                         e
@@ -114,51 +318,186 @@ impl App {
                 "Can't use both an execution filter expression and a file at the same time",
             )),
         }?;
+        // Check that the batch key template references existing
+        // capture groups, since unknown ones expand to nothing
+        let batch_key_groups = if let Some(batch_key) = &settings.batch_key {
+            batch_key_groups(batch_key, &match_key_re)?
+        } else {
+            Vec::new()
+        };
+        // Compile batch key expression
+        let batch_key_filter = match (&settings.batch_key, &settings.batch_key_expr) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "Can't use both a batch key template and an expression at the same time"
+            )),
+            (_, Some(expr)) => jq::compile(expr, &[])
+                .map(Some)
+                .map_err(|e| anyhow!("Failed to compile batch key expression: {:?}", e)),
+            _ => Ok(None),
+        }?;
         // Gather handler command
         let mut handler_command_args = VecDeque::from(args_os().skip(1).collect::<Vec<OsString>>());
         let handler_command_program = handler_command_args
             .pop_front()
             .ok_or(anyhow!("empty handler command"))?;
+        // The removal handler command is given as a shell command
+        let (removal_handler_command_program, removal_handler_command_args) =
+            match &settings.removal_handler_command {
+                Some(removal_handler_command) if !removal_handler_command.trim().is_empty() => (
+                    Some(OsString::from("sh")),
+                    VecDeque::from([
+                        OsString::from("-c"),
+                        OsString::from(removal_handler_command),
+                    ]),
+                ),
+                Some(_) => return Err(anyhow!("empty removal handler command")),
+                None => (None, VecDeque::new()),
+            };
         // Done
         Ok(App {
             settings,
             match_key_re,
+            template_placeholder_re,
+            match_event_names_res,
             pull_match_key_res,
             execution_filter,
+            batch_key_groups,
+            batch_key_filter,
             handler_command_program,
             handler_command_args,
+            removal_handler_command_program,
+            removal_handler_command_args,
         })
     }
 
-    /// Group events according to common bucket and key prefixes.
-    pub fn batch_events<I>(&self, records: I) -> Vec<EventBatch>
+    /// Determine whether an event was caused by an object uploaded by
+    /// the event bridge itself, as indicated by the upload marker.
+    async fn is_own_upload(
+        &self,
+        bucket: &str,
+        record: &S3EventRecord,
+        client: &'static aws_sdk_s3::Client,
+    ) -> error::Result<bool> {
+        let key = record.s3.object.key.as_deref().unwrap_or_default();
+        let head_output = if let Some(head_output) = head(client, bucket, key).await? {
+            head_output
+        } else {
+            return Ok(false);
+        };
+        // The object could've been replaced after the event was
+        // emitted, in which case the marker says nothing about it
+        if let (Some(event_e_tag), Some(current_e_tag)) =
+            (&record.s3.object.e_tag, head_output.e_tag())
+        {
+            if event_e_tag.trim_matches('"') != current_e_tag.trim_matches('"') {
+                return Ok(false);
+            }
+        }
+        Ok(head_output
+            .metadata()
+            .and_then(|metadata| metadata.get(MARKER_METADATA_KEY))
+            .is_some_and(|marker| *marker == self.settings.upload_marker))
+    }
+
+    /// Group events according to common bucket and key prefixes. Each
+    /// event is paired with an identifier of its source, which is
+    /// kept in the batch it ends up in.
+    pub async fn batch_events<I>(
+        &self,
+        records: I,
+        client: &'static aws_sdk_s3::Client,
+    ) -> Vec<EventBatch>
     where
-        I: Iterator<Item = S3EventRecord>,
+        I: Iterator<Item = (String, S3EventRecord)>,
     {
-        let mut batches = BTreeSet::new();
-        for record in records {
-            let processed = (|| {
+        let mut batches = BTreeMap::new();
+        for (source, mut record) in records {
+            let processed = (|| -> Result<_> {
+                let event_name = record.event_name.as_deref().unwrap_or_default();
+                if !self.match_event_names_res.is_empty()
+                    && !self
+                        .match_event_names_res
+                        .iter()
+                        .any(|re| re.is_match(event_name))
+                {
+                    return Err(anyhow!(
+                        "S3 event record has event name {:?} \
+                         that doesn't match configured patterns {:?}; ignoring",
+                        event_name,
+                        self.settings.match_event_names
+                    ));
+                }
+                let removal = self.removal_handler_command_program.is_some()
+                    && event_name.starts_with("ObjectRemoved:");
                 let key = record
                     .s3
                     .object
                     .key
-                    .as_ref()
+                    .as_deref()
                     .ok_or_else(|| anyhow!("S3 event record is missing an object key"))?;
-                if !self.match_key_re.is_match(key) {
-                    return Err(anyhow!(
+                // Keep the decoded key in the record, so that it's
+                // seen as such everywhere else
+                let key = decode_key(key)?;
+                record.s3.object.key = Some(key.clone());
+                let key_captures = self.match_key_re.captures(&key).ok_or_else(|| {
+                    anyhow!(
                         "S3 event record has object key {:?} \
                          that doesn't match configured pattern {:?}; ignoring",
                         key,
                         self.settings.match_key
-                    ));
-                }
+                    )
+                })?;
+                let captures = self
+                    .match_key_re
+                    .capture_names()
+                    .flatten()
+                    .filter_map(|name| {
+                        key_captures
+                            .name(name)
+                            .map(|m| (name.to_string(), m.as_str().to_string()))
+                    })
+                    .collect::<BTreeMap<String, String>>();
                 let bucket = record
                     .s3
                     .bucket
                     .name
                     .clone()
                     .ok_or_else(|| anyhow!("S3 event record is missing a bucket name"))?;
-                let prefix = if self.settings.pull_parent_dirs < 0 {
+                let prefix = if let Some(batch_key) = &self.settings.batch_key {
+                    // Optional groups that didn't participate in the
+                    // match would expand to nothing
+                    for group in &self.batch_key_groups {
+                        let matched = match group.parse::<usize>() {
+                            Ok(index) => key_captures.get(index),
+                            Err(_) => key_captures.name(group),
+                        };
+                        if matched.is_none() {
+                            return Err(anyhow!(
+                                "S3 event record has object key {:?} without a match \
+                                 for capture group {:?} used by the batch key; ignoring",
+                                key,
+                                group
+                            ));
+                        }
+                    }
+                    let mut prefix = String::new();
+                    key_captures.expand(batch_key, &mut prefix);
+                    prefix
+                } else if let Some(batch_key_filter) = &self.batch_key_filter {
+                    let input = json!({"record": &record, "captures": &captures});
+                    match jq::first_result(batch_key_filter, input, Vec::new())
+                        .transpose()
+                        .context("Failed to evaluate batch key expression")?
+                    {
+                        Some(Value::String(prefix)) => prefix,
+                        result => {
+                            return Err(anyhow!(
+                                "Batch key expression returned {:?} instead of a string",
+                                result
+                            ))
+                        }
+                    }
+                } else if self.settings.pull_parent_dirs < 0 {
                     String::from("")
                 } else {
                     let mut prefix_parts = key
@@ -175,19 +514,56 @@ impl App {
                     }
                     prefix_parts
                 };
-                Ok((bucket, prefix))
+                // An empty derived prefix would pull (and possibly
+                // delete from) the whole bucket
+                let derived = self.settings.batch_key.is_some() || self.batch_key_filter.is_some();
+                if derived && prefix.is_empty() {
+                    return Err(anyhow!(
+                        "S3 event record has object key {:?} \
+                         for which the batch key derived an empty prefix; ignoring",
+                        key
+                    ));
+                }
+                Ok((bucket, prefix, captures, removal))
             })();
-            if let Ok((bucket, prefix)) = processed {
-                batches.insert((bucket, prefix));
+            if let Ok((bucket, prefix, captures, removal)) = processed {
+                // Removed objects can't be inspected for a marker
+                if self.settings.skip_own_uploads && !removal {
+                    match self.is_own_upload(&bucket, &record, client).await {
+                        Ok(true) => {
+                            info!(
+                                "Skipped event record {:?} caused by the event bridge itself",
+                                record.s3.object.key
+                            );
+                            continue;
+                        }
+                        Ok(false) => (),
+                        Err(e) => {
+                            warn!(
+                                "Couldn't determine the origin of event record {:?}: {:?}",
+                                record.s3.object.key, e
+                            );
+                        }
+                    }
+                }
+                let batch = batches
+                    .entry((bucket.clone(), prefix.clone(), removal))
+                    .or_insert_with(|| EventBatch {
+                        bucket,
+                        prefix,
+                        captures,
+                        event_time: Some(record.event_time),
+                        removal,
+                        ..Default::default()
+                    });
+                batch.sources.insert(source);
+                batch.records.push(record);
             } else {
                 info!("Skipped event record {:?}", processed);
             }
         }
 
-        batches
-            .into_iter()
-            .map(|(bucket, prefix)| EventBatch { bucket, prefix })
-            .collect()
+        batches.into_values().collect()
     }
 
     /// List the input objects before any filtering.
@@ -195,17 +571,17 @@ impl App {
         &self,
         batch: &EventBatch,
         client: &'static aws_sdk_s3::Client,
-    ) -> Result<Vec<Object>> {
+    ) -> error::Result<Vec<Object>> {
         let mut next = None;
         let mut objects = Vec::new();
         loop {
             let (page, next_token) = list_keys(client, &batch.bucket, &batch.prefix, &next)
                 .await
-                .with_context(|| {
-                    format!(
+                .map_err(|e| {
+                    e.context(format!(
                         "Failed to list keys under {:?} in bucket {:?}",
                         &batch.prefix, &batch.bucket
-                    )
+                    ))
                 })?;
             objects.extend(page);
             if next_token.is_none() {
@@ -217,27 +593,70 @@ impl App {
         Ok(objects)
     }
 
-    /// Run the execution filter with the given objects as inputs.
-    async fn evaluate_execution_filter(&self, objects: &[Object]) -> Result<Option<Result<Value>>> {
-        if let Some(filter) = &self.execution_filter {
-            serialize_objects(objects)
-                .context("Failed to serialize objects for execution filter")
-                .map(|input| jq::first_result(filter, input))
+    /// Run the execution filter with the given objects as inputs, and
+    /// the batch as [`FILTER_VARIABLES`], and interpret its results as
+    /// directives. Unless `execution_filter_fan_out` is enabled, only
+    /// the first result is considered, and it isn't kept alongside
+    /// its directives.
+    async fn evaluate_execution_filter(
+        &self,
+        batch: &EventBatch,
+        objects: &[Object],
+    ) -> error::Result<Vec<(FilterDirectives, Option<Value>)>> {
+        let filter = if let Some(filter) = &self.execution_filter {
+            filter
+        } else {
+            return Ok(vec![(Default::default(), None)]);
+        };
+        let input = serialize_objects(objects)
+            .context("Failed to serialize objects for execution filter")
+            .map_err(Error::Filter)?;
+        let vars = vec![
+            json!(batch.bucket),
+            json!(batch.prefix),
+            serde_json::to_value(&batch.records)
+                .context("Failed to serialize event records for execution filter")
+                .map_err(Error::Filter)?,
+            json!(batch.captures),
+        ];
+        if self.settings.execution_filter_fan_out {
+            let results = jq::all_results(filter, input, vars)
+                .context("Failed to evaluate execution filter")
+                .map_err(Error::Filter)?;
+            results
+                .into_iter()
+                .map(|result| {
+                    FilterDirectives::from_result(Some(result.clone()))
+                        .map(|directives| (directives, Some(result)))
+                        .map_err(Error::Directives)
+                })
+                .collect()
         } else {
-            Ok(None)
+            let result = jq::first_result(filter, input, vars)
+                .transpose()
+                .context("Failed to evaluate execution filter")
+                .map_err(Error::Filter)?;
+            let directives = FilterDirectives::from_result(result).map_err(Error::Directives)?;
+            Ok(vec![(directives, None)])
         }
     }
 
-    /// Download all matching objects to the given path.
+    /// Download all matching objects to the given path, or only the
+    /// ones with the given keys, if any. Returns the downloaded keys
+    /// alongside their local paths.
     async fn download_objects(
         &self,
         batch: &EventBatch,
         client: &'static aws_sdk_s3::Client,
         target_path: &Path,
         objects: &[Object],
-    ) -> Result<()> {
-        let mut joinset: JoinSet<Result<String>> = JoinSet::new();
+        pull: Option<&BTreeSet<String>>,
+    ) -> error::Result<Vec<(String, PathBuf)>> {
+        let mut joinset: JoinSet<error::Result<(String, PathBuf)>> = JoinSet::new();
         for obj in objects.iter().filter(|obj| {
+            if let (Some(pull), Some(k)) = (pull, obj.key()) {
+                return pull.contains(k);
+            }
             self.pull_match_key_res.iter().any(|re| {
                 if let Some(k) = obj.key() {
                     re.is_match(k)
@@ -253,19 +672,158 @@ impl App {
             joinset.spawn(async move {
                 download(client, &bucket, &obj_key, &local_path)
                     .await
-                    .with_context(|| {
-                        format!(
+                    .map_err(|e| {
+                        e.context(format!(
                             "Failed to download object {:?} from bucket {:?}",
                             &obj_key, &bucket
-                        )
+                        ))
                     })?;
-                Ok(obj_key)
+                Ok((obj_key, local_path))
             });
         }
-        while let Some(downloaded_obj_key) = joinset.join_next().await {
-            info!("Downloaded {:?}", downloaded_obj_key??);
+        let mut downloaded = Vec::new();
+        while let Some(downloaded_obj) = joinset.join_next().await {
+            let (obj_key, local_path) = downloaded_obj??;
+            info!("Downloaded {:?}", obj_key);
+            downloaded.push((obj_key, local_path));
         }
-        Ok(())
+        Ok(downloaded)
+    }
+
+    /// Select the program and arguments of the command that handles
+    /// the given batch.
+    fn handler_command(&self, batch: &EventBatch) -> (&OsString, &VecDeque<OsString>) {
+        match (&self.removal_handler_command_program, batch.removal) {
+            (Some(program), true) => (program, &self.removal_handler_command_args),
+            _ => (&self.handler_command_program, &self.handler_command_args),
+        }
+    }
+
+    /// Render the given key template using the batch's attributes,
+    /// the named capture groups of `match_key` and the time of the
+    /// batch's first event.
+    fn render_template(&self, template: &str, batch: &EventBatch) -> String {
+        let time = batch.event_time.unwrap_or_else(Utc::now);
+        self.template_placeholder_re
+            .replace_all(template, |captures: &Captures| {
+                let name = &captures[1];
+                if let Some(value) = batch.captures.get(name) {
+                    return value.clone();
+                }
+                match name {
+                    "bucket" => batch.bucket.clone(),
+                    "prefix" => batch.prefix.clone(),
+                    "year" => format!("{:04}", time.year()),
+                    "month" => format!("{:02}", time.month()),
+                    "day" => format!("{:02}", time.day()),
+                    "hour" => format!("{:02}", time.hour()),
+                    "minute" => format!("{:02}", time.minute()),
+                    "second" => format!("{:02}", time.second()),
+                    _ => String::new(),
+                }
+            })
+            .into_owned()
+    }
+
+    /// Compute the key prefix under which outputs are placed.
+    fn target_prefix(&self, batch: &EventBatch) -> String {
+        self.settings
+            .target_prefix
+            .as_ref()
+            .map(|template| self.render_template(template, batch))
+            .unwrap_or_else(|| batch.prefix.clone())
+    }
+
+    /// Classify the exit status of the handler command according to
+    /// the configured exit code policy.
+    fn classify_exit_status(&self, status: ExitStatus) -> Outcome {
+        match status.code() {
+            Some(code) if self.settings.handler_success_exit_codes.contains(&code) => {
+                Outcome::Succeeded
+            }
+            Some(code)
+                if self
+                    .settings
+                    .handler_permanent_failure_exit_codes
+                    .contains(&code) =>
+            {
+                Outcome::PermanentFailure(status)
+            }
+            _ => Outcome::RetryableFailure(status),
+        }
+    }
+
+    /// Upload a report of a permanent failure of the handler command
+    /// to the target bucket, if configured to do so.
+    async fn report_failure(
+        &self,
+        batch: &EventBatch,
+        client: &'static aws_sdk_s3::Client,
+        target_bucket: &str,
+        status: ExitStatus,
+    ) -> error::Result<()> {
+        let failure_report_prefix = if let Some(template) = &self.settings.failure_report_prefix {
+            self.render_template(template, batch)
+        } else {
+            return Ok(());
+        };
+        let time = Utc::now();
+        let (program, args) = self.handler_command(batch);
+        let report = FailureReport {
+            bucket: &batch.bucket,
+            prefix: &batch.prefix,
+            command: std::iter::once(program)
+                .chain(args)
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+            exit_code: status.code(),
+            time,
+        };
+        let contents = serde_json::to_vec(&report)
+            .context("Failed to serialize failure report")
+            .map_err(Error::Internal)?;
+        let key = timestamped_key(&failure_report_prefix, time, "json");
+        info!(key = ?key, "Uploading failure report");
+        upload_contents(
+            client,
+            target_bucket,
+            contents,
+            "application/json",
+            &key,
+            &self.settings.upload_marker,
+        )
+        .await
+    }
+
+    /// Upload the captured output of the handler command to the
+    /// target bucket, if configured to do so.
+    async fn upload_handler_log(
+        &self,
+        batch: &EventBatch,
+        client: &'static aws_sdk_s3::Client,
+        target_bucket: &str,
+        output: Vec<String>,
+    ) -> error::Result<()> {
+        let handler_log_prefix = if let Some(template) = &self.settings.handler_log_prefix {
+            self.render_template(template, batch)
+        } else {
+            return Ok(());
+        };
+        let key = timestamped_key(&handler_log_prefix, Utc::now(), "log");
+        let mut contents = output.join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        info!(key = ?key, "Uploading handler log");
+        upload_contents(
+            client,
+            target_bucket,
+            contents.into_bytes(),
+            "text/plain; charset=utf-8",
+            &key,
+            &self.settings.upload_marker,
+        )
+        .await
     }
 
     /// Upload all given objects to the target bucket.
@@ -276,25 +834,32 @@ impl App {
         base_path: &Path,
         target_bucket: &str,
         paths: &[PathBuf],
-    ) -> Result<()> {
-        let mut joinset: JoinSet<Result<String>> = JoinSet::new();
+    ) -> error::Result<()> {
+        let target_prefix = self.target_prefix(batch);
+        let mut joinset: JoinSet<error::Result<String>> = JoinSet::new();
         for path in paths {
             let path = path.clone();
             let bucket = target_bucket.to_owned();
-            let storage_key_path =
-                Path::new(&batch.prefix).join(path.strip_prefix(base_path).with_context(|| {
-                    format!(
-                        "Failed to convert local file path \
-                         to bucket path for {:?} (using base path {:?})",
-                        path, base_path
-                    )
-                })?);
+            let storage_key_path = Path::new(&target_prefix).join(
+                path.strip_prefix(base_path)
+                    .with_context(|| {
+                        format!(
+                            "Failed to convert local file path \
+                             to bucket path for {:?} (using base path {:?})",
+                            path, base_path
+                        )
+                    })
+                    .map_err(Error::Internal)?,
+            );
             let storage_key = storage_key_path.to_string_lossy().to_string();
+            let marker = self.settings.upload_marker.clone();
             joinset.spawn(async move {
                 info!(key = ?storage_key, "Uploading file");
-                upload(client, &bucket, &path, &storage_key)
+                upload(client, &bucket, &path, &storage_key, &marker)
                     .await
-                    .with_context(|| format!("Failed to upload file to {:?}", &storage_key))?;
+                    .map_err(|e| {
+                        e.context(format!("Failed to upload file to {:?}", &storage_key))
+                    })?;
                 Ok(storage_key)
             });
         }
@@ -304,94 +869,290 @@ impl App {
         Ok(())
     }
 
-    /// Handle a batch of S3 event records.
-    #[instrument(skip(self, client))]
-    pub async fn handle(
+    /// Execute the handler command once, over a fresh download of the
+    /// batch's objects, as directed by the execution filter. The
+    /// handler command is terminated if it runs past
+    /// `handler_timeout`, or past the given deadline, whichever comes
+    /// first. Objects deleted through `propagate_deletions` are taken
+    /// out of the pending objects, so that later executions don't
+    /// attempt to download them.
+    async fn execute(
         &self,
         batch: &EventBatch,
         client: &'static aws_sdk_s3::Client,
-    ) -> Result<()> {
-        let base_dir = TempDir::new().context("Failed to create temporary directory")?;
+        deadline: Option<Instant>,
+        pending_objects: &mut Vec<Object>,
+        directives: &FilterDirectives,
+        filter_result: Option<&Value>,
+    ) -> error::Result<Outcome> {
+        let base_dir = TempDir::new()
+            .context("Failed to create temporary directory")
+            .map_err(Error::LocalIo)?;
         let base_path = base_dir.path();
         info!(
             path = ?base_path,
             "Created temporary directory to hold input and output files"
         );
+        let output_dir = if self.settings.output_folder_var.is_some() {
+            let output_dir = TempDir::new()
+                .context("Failed to create temporary output directory")
+                .map_err(Error::LocalIo)?;
+            info!(
+                path = ?output_dir.path(),
+                "Created temporary directory to hold output files"
+            );
+            Some(output_dir)
+        } else {
+            None
+        };
+        // The events file is kept apart from the input files, so that
+        // it isn't mistaken for an output
+        let events_dir = TempDir::new()
+            .context("Failed to create temporary events directory")
+            .map_err(Error::LocalIo)?;
+        let events_path = events_dir.path().join("events.json");
+        let events = serde_json::to_vec(&batch.records)
+            .context("Failed to serialize event records")
+            .map_err(Error::Internal)?;
+        tokio::fs::write(&events_path, events)
+            .await
+            .with_context(|| format!("Failed to write event records to {:?}", events_path))
+            .map_err(Error::LocalIo)?;
         let target_bucket = self
             .settings
             .target_bucket
             .clone()
             .unwrap_or_else(|| batch.bucket.clone());
 
-        // First: list all relevant objects from S3
-        info!("Listing input objects");
-        let pending_objects = self.list_input_objects(batch, client).await?;
-
-        // Second: run the filter expression on all candidate objects
-        info!("Evaluating execution filter");
-        match self.evaluate_execution_filter(&pending_objects).await? {
-            Some(Ok(v)) if v == json!(false) => {
-                info!(
-                    "Execution filter returned 'false'; stopping before download of {:?} files",
-                    pending_objects.len()
-                );
-                return Ok(());
-            }
-            _ => {
-                info!("Execution filter didn't return 'false'; proceeding to download");
-            }
-        }
-
-        // Third: pull all relevant files
+        // First: pull all relevant files
         info!("Downloading input objects");
-        self.download_objects(batch, client, base_path, &pending_objects)
+        let downloaded = self
+            .download_objects(
+                batch,
+                client,
+                base_path,
+                pending_objects,
+                directives.pull.as_ref(),
+            )
             .await?;
 
-        // Fourth: compute a signature for each file pulled
-        let signatures = if target_bucket == batch.bucket {
+        // Second: compute a signature for each file pulled, unless
+        // outputs are placed in a separate folder
+        let signatures = if output_dir.is_none() && target_bucket == batch.bucket {
             compute_signatures(base_path)
-                .with_context(|| format!("Failed to compute signatures in {:?}", base_path))
+                .map_err(|e| e.context(format!("Failed to compute signatures in {:?}", base_path)))
         } else {
             empty_signatures()
         }?;
 
-        // Fifth: invoke the handler program
-        info!(
-            "Invoking handler command {:?} {:?}",
-            &self.handler_command_program, &self.handler_command_args
-        );
-        let status = Command::new(&self.handler_command_program)
-            .args(&self.handler_command_args)
+        // Third: invoke the handler program
+        let (program, args) = self.handler_command(batch);
+        info!("Invoking handler command {:?} {:?}", program, args);
+        let mut command = Command::new(program);
+        // Variables given by the execution filter can't override the
+        // ones set by the event bridge
+        command
+            .args(args)
+            .envs(directives.env.iter().map(|(name, value)| {
+                (
+                    name,
+                    value
+                        .as_str()
+                        .map(String::from)
+                        .unwrap_or_else(|| value.to_string()),
+                )
+            }))
             .env(&self.settings.root_folder_var, base_path)
             .env(&self.settings.bucket_var, &batch.bucket)
             .env(&self.settings.key_prefix_var, &batch.prefix)
-            .status()
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to execute program {:?} with args {:?}",
-                    &self.handler_command_program, &self.handler_command_args
-                )
+            .env(&self.settings.events_file_var, &events_path);
+        if let (Some(output_folder_var), Some(output_dir)) =
+            (&self.settings.output_folder_var, &output_dir)
+        {
+            command.env(output_folder_var, output_dir.path());
+        }
+        if let Some(filter_result) = filter_result {
+            command.env(&self.settings.filter_result_var, filter_result.to_string());
+        }
+        let deadline = self
+            .settings
+            .handler_timeout
+            .map(|handler_timeout| Instant::now() + Duration::from_secs(handler_timeout))
+            .into_iter()
+            .chain(deadline)
+            .min();
+        // Output is only kept in memory when it's going to be uploaded
+        let mut output = Vec::new();
+        let capture_output =
+            self.settings.capture_handler_output || self.settings.handler_log_prefix.is_some();
+        let result = process::run(
+            command,
+            deadline,
+            Duration::from_secs(self.settings.handler_kill_grace_period),
+            capture_output,
+            self.settings
+                .handler_log_prefix
+                .is_some()
+                .then_some(&mut output),
+        )
+        .instrument(info_span!(
+            "handler",
+            bucket = %batch.bucket,
+            prefix = %batch.prefix
+        ))
+        .await;
+        let succeeded = result
+            .as_ref()
+            .is_ok_and(|status| self.classify_exit_status(*status) == Outcome::Succeeded);
+        if !succeeded || !self.settings.handler_log_only_on_failure {
+            // The log is auxiliary, so failing to upload it shouldn't
+            // mask the handler's own result
+            if let Err(e) = self
+                .upload_handler_log(batch, client, &target_bucket, output)
+                .await
+            {
+                warn!("Failed to upload handler log: {}", e);
+            }
+        }
+        let status = result.map_err(|e| {
+            e.context(format!(
+                "Failed to execute program {:?} with args {:?}",
+                program, args
+            ))
+        })?;
+        let outcome = self.classify_exit_status(status);
+        match outcome {
+            Outcome::RetryableFailure(_) => {
+                warn!(status = ?status, "Handler command failed; the events may be retried");
+                return Ok(outcome);
+            }
+            Outcome::PermanentFailure(_) => {
+                error!(status = ?status, "Handler command failed permanently");
+                // Retrying a permanent failure just to upload its
+                // report would run the handler command again
+                if let Err(e) = self
+                    .report_failure(batch, client, &target_bucket, status)
+                    .await
+                {
+                    warn!("Failed to upload failure report: {}", e);
+                }
+                return Ok(outcome);
+            }
+            _ => (),
+        }
+
+        // Fourth: upload the changed files, or every output file
+        if let Some(output_dir) = &output_dir {
+            let output_path = output_dir.path();
+            let outputs = list_files(output_path).map_err(|e| {
+                e.context(format!("Failed to list output files in {:?}", output_path))
             })?;
-        if !status.success() {
-            warn!(status = ?status, "Handler command was not successful");
-            return Ok(());
+            info!(total = outputs.len(), "Uploading output files");
+            self.upload_objects(batch, client, output_path, &target_bucket, &outputs)
+                .await?;
+        } else {
+            let differences = find_signature_differences(base_path, &signatures).map_err(|e| {
+                e.context(format!(
+                    "Failed to compute signature differences in {:?}",
+                    base_path
+                ))
+            })?;
+            info!(
+                total = differences.len(),
+                "Uploading files with found differences"
+            );
+            self.upload_objects(batch, client, base_path, &target_bucket, &differences)
+                .await?;
         }
 
-        // Sixth: upload the changed files
-        let differences =
-            find_signature_differences(base_path, &signatures).with_context(|| {
-                format!("Failed to compute signature differences in {:?}", base_path)
+        // Fifth: delete the objects whose files were removed
+        if self.settings.propagate_deletions {
+            let removed = downloaded
+                .into_iter()
+                .filter(|(_, local_path)| !local_path.exists())
+                .map(|(obj_key, _)| obj_key)
+                .collect::<Vec<String>>();
+            info!(total = removed.len(), "Deleting objects with removed files");
+            delete(client, &batch.bucket, &removed).await.map_err(|e| {
+                e.context(format!(
+                    "Failed to delete removed objects from bucket {:?}",
+                    &batch.bucket
+                ))
             })?;
+            let removed = removed.into_iter().collect::<BTreeSet<String>>();
+            pending_objects.retain(|obj| obj.key().is_some_and(|key| !removed.contains(key)));
+        }
+
+        // Done
+        Ok(Outcome::Succeeded)
+    }
+
+    /// Handle a batch of S3 event records, executing the handler
+    /// command once, or once per result of the execution filter if
+    /// `execution_filter_fan_out` is enabled.
+    #[instrument(
+        skip(self, client, batch),
+        fields(bucket = %batch.bucket, prefix = %batch.prefix, records = batch.records.len())
+    )]
+    pub async fn handle(
+        &self,
+        batch: &EventBatch,
+        client: &'static aws_sdk_s3::Client,
+        deadline: Option<Instant>,
+    ) -> error::Result<Outcome> {
+        // List all relevant objects from S3
+        info!("Listing input objects");
+        let mut pending_objects = self.list_input_objects(batch, client).await?;
+
+        // Run the filter expression on all candidate objects
+        info!("Evaluating execution filter");
+        let results = self
+            .evaluate_execution_filter(batch, &pending_objects)
+            .await?;
+        if let Some(delay) = results
+            .iter()
+            .filter_map(|(directives, _)| directives.defer)
+            .max()
+        {
+            info!("Execution filter deferred execution by {:?} seconds", delay);
+            return Ok(Outcome::Deferred(Duration::from_secs(delay)));
+        }
+        let runs = results
+            .into_iter()
+            .filter(|(directives, _)| directives.run)
+            .collect::<Vec<(FilterDirectives, Option<Value>)>>();
+        if runs.is_empty() {
+            info!(
+                "Execution filter returned 'false' or no results; \
+                 stopping before download of {:?} files",
+                pending_objects.len()
+            );
+            return Ok(Outcome::Skipped);
+        }
         info!(
-            total = differences.len(),
-            "Uploading files with found differences"
+            total = runs.len(),
+            "Execution filter didn't return 'false'; proceeding to download"
         );
-        self.upload_objects(batch, client, base_path, &target_bucket, &differences)
-            .await?;
 
-        // Done
-        Ok(())
+        // Execute the handler command for each run, stopping at the
+        // first one that doesn't succeed
+        for (directives, filter_result) in &runs {
+            match self
+                .execute(
+                    batch,
+                    client,
+                    deadline,
+                    &mut pending_objects,
+                    directives,
+                    filter_result.as_ref(),
+                )
+                .await?
+            {
+                Outcome::Succeeded => (),
+                outcome => return Ok(outcome),
+            }
+        }
+        Ok(Outcome::Succeeded)
     }
 }
 
@@ -476,6 +1237,68 @@ impl<'fields> SerializableObject<'fields> {
     }
 }
 
+/// Decodes an object key as found in S3 event notifications, which
+/// are URL-encoded with spaces replaced by `+`.
+fn decode_key(key: &str) -> Result<String> {
+    percent_decode_str(&key.replace('+', " "))
+        .decode_utf8()
+        .map(String::from)
+        .with_context(|| format!("S3 event record has malformed object key {:?}", key))
+}
+
+/// Builds a regex that matches the whole of a string against a glob
+/// pattern, where `*` matches any sequence of characters.
+fn glob_to_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let expr = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+    Regex::new(&format!("^{}$", expr))
+}
+
+/// Lists the capture groups of the key matching regex referenced by
+/// a batch key template, failing if any of them doesn't exist.
+fn batch_key_groups(batch_key: &str, match_key_re: &Regex) -> Result<Vec<String>> {
+    let group_re = Regex::new(r"\$(?:\$|\{([^}]*)\}|([_0-9a-zA-Z]+))")?;
+    let mut groups = Vec::new();
+    for captures in group_re.captures_iter(batch_key) {
+        // Escaped dollar signs reference no group
+        let group = if let Some(group) = captures.get(1).or_else(|| captures.get(2)) {
+            group.as_str()
+        } else {
+            continue;
+        };
+        let exists = match group.parse::<usize>() {
+            Ok(index) => index < match_key_re.captures_len(),
+            Err(_) => match_key_re
+                .capture_names()
+                .flatten()
+                .any(|name| name == group),
+        };
+        if !exists {
+            return Err(anyhow!(
+                "Batch key {:?} references unknown capture group {:?}",
+                batch_key,
+                &captures[0]
+            ));
+        }
+        groups.push(group.to_string());
+    }
+    Ok(groups)
+}
+
+/// Builds an object key made of the given prefix, a timestamp and an
+/// extension.
+fn timestamped_key(prefix: &str, time: DateTime<Utc>, extension: &str) -> String {
+    format!(
+        "{}{}.{}",
+        prefix,
+        time.format("%Y%m%dT%H%M%S%.6fZ"),
+        extension
+    )
+}
+
 /// Serializes a vector of S3 objects as an input to the execution
 /// filter. Reference:
 /// https://docs.aws.amazon.com/AmazonS3/latest/API/API_Object.html
@@ -486,3 +1309,99 @@ fn serialize_objects(objects: &[Object]) -> Result<Value> {
         .collect::<Vec<SerializableObject>>();
     serde_json::to_value(converted).context("Failed serialization of S3 objects")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_key_groups, decode_key, FilterDirectives};
+    use regex::Regex;
+    use serde_json::json;
+
+    #[test]
+    fn decode_key_keeps_plain_keys() {
+        assert_eq!(
+            decode_key("inputs/2023/data.csv").unwrap(),
+            "inputs/2023/data.csv"
+        );
+    }
+
+    #[test]
+    fn decode_key_replaces_plus_with_space() {
+        assert_eq!(
+            decode_key("inputs/my+folder/some+file.txt").unwrap(),
+            "inputs/my folder/some file.txt"
+        );
+    }
+
+    #[test]
+    fn decode_key_decodes_percent_escapes() {
+        assert_eq!(
+            decode_key("inputs/a%2Bb%3Dc%26d.txt").unwrap(),
+            "inputs/a+b=c&d.txt"
+        );
+        assert_eq!(decode_key("inputs/100%25.txt").unwrap(), "inputs/100%.txt");
+    }
+
+    #[test]
+    fn decode_key_decodes_unicode() {
+        assert_eq!(
+            decode_key("entradas/a%C3%B1o+2023/caf%C3%A9.json").unwrap(),
+            "entradas/año 2023/café.json"
+        );
+    }
+
+    #[test]
+    fn decode_key_rejects_invalid_utf8() {
+        assert!(decode_key("inputs/%FF%FE.txt").is_err());
+    }
+
+    #[test]
+    fn filter_directives_keep_plain_results() {
+        assert!(FilterDirectives::from_result(None).unwrap().run);
+        assert!(
+            FilterDirectives::from_result(Some(json!(true)))
+                .unwrap()
+                .run
+        );
+        assert!(
+            FilterDirectives::from_result(Some(json!([1, 2])))
+                .unwrap()
+                .run
+        );
+        assert!(
+            !FilterDirectives::from_result(Some(json!(false)))
+                .unwrap()
+                .run
+        );
+    }
+
+    #[test]
+    fn filter_directives_read_objects() {
+        let directives = FilterDirectives::from_result(Some(json!({
+            "env": {"MODE": "full", "PARTS": 3},
+            "pull": ["inputs/a.csv", "inputs/b.csv"]
+        })))
+        .unwrap();
+        assert!(directives.run);
+        assert_eq!(directives.defer, None);
+        assert_eq!(directives.env["MODE"], json!("full"));
+        assert_eq!(directives.env["PARTS"], json!(3));
+        assert_eq!(directives.pull.unwrap().len(), 2);
+
+        let directives = FilterDirectives::from_result(Some(json!({"defer": 60}))).unwrap();
+        assert_eq!(directives.defer, Some(60));
+
+        assert!(FilterDirectives::from_result(Some(json!({"defer": -1}))).is_err());
+        assert!(FilterDirectives::from_result(Some(json!({"pull": "inputs/a.csv"}))).is_err());
+    }
+
+    #[test]
+    fn batch_key_groups_lists_referenced_groups() {
+        let re = Regex::new(r"^(?P<tenant>tenant=[^/]+)/(?:.*/)?(?P<date>date=[^/]+)/").unwrap();
+        assert_eq!(
+            batch_key_groups("${tenant}/$date/$$1/$0", &re).unwrap(),
+            vec!["tenant", "date", "0"]
+        );
+        assert!(batch_key_groups("$tenant/$dat/", &re).is_err());
+        assert!(batch_key_groups("$tenant/$3/", &re).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use s3_event_bridge::{app, client};
 use std::env::var;
 
@@ -18,12 +18,24 @@ async fn main() -> Result<()> {
         var(&app::current().settings.bucket_var).context(&app::current().settings.bucket_var)?;
     let prefix = var(&app::current().settings.key_prefix_var)
         .context(&app::current().settings.key_prefix_var)?;
-    let batch = app::EventBatch { bucket, prefix };
+    let batch = app::EventBatch {
+        bucket,
+        prefix,
+        ..Default::default()
+    };
 
-    app::current()
-        .handle(&batch, client::current())
+    match app::current()
+        .handle(&batch, client::current(), None)
         .await
-        .with_context(|| format!("Failed to handle batch of records {:?}", &batch))?;
-
-    Ok(())
+        .with_context(|| format!("Failed to handle batch of records {:?}", &batch))?
+    {
+        app::Outcome::RetryableFailure(status) | app::Outcome::PermanentFailure(status) => {
+            Err(anyhow!("Handler command failed with {}", status))
+        }
+        app::Outcome::Deferred(delay) => Err(anyhow!(
+            "Execution filter deferred execution by {:?} seconds",
+            delay.as_secs()
+        )),
+        _ => Ok(()),
+    }
 }
@@ -1,29 +1,276 @@
 use anyhow::{anyhow, Context, Result};
-use aws_lambda_events::event::{s3::S3Event, sqs::SqsEventObj};
+use aws_lambda_events::event::{
+    s3::S3EventRecord,
+    sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent, SqsMessage},
+};
+use aws_sdk_sqs::Client;
 use lambda_runtime::{run, service_fn, LambdaEvent};
-use s3_event_bridge::{app, client};
-
-/// Handle each S3 event record through the handler program
-async fn function_handler(event: LambdaEvent<SqsEventObj<S3Event>>) -> Result<()> {
-    for batch in app::current().batch_events(
-        event
-            .payload
-            .records
-            .into_iter()
-            .flat_map(|record| record.body.records),
-    ) {
-        app::current()
-            .handle(&batch, client::current())
+use s3_event_bridge::{app, client, conf, event, sqs};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env::var,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// The amount of seconds reserved at the end of each invocation to
+/// upload the outputs of a terminated handler command and report
+/// results, in addition to the handler's kill grace period.
+const DEADLINE_MARGIN: u64 = 5;
+
+/// The sources of the batches that weren't handled, and should be
+/// received again.
+#[derive(Default)]
+struct Unhandled {
+    /// The sources of batches that failed and may be retried.
+    failed: BTreeSet<String>,
+
+    /// The sources of batches deferred by the execution filter, each
+    /// with the longest delay requested for it.
+    deferred: BTreeMap<String, Duration>,
+}
+
+/// Compute the deadline for handler commands, given the invocation
+/// deadline in milliseconds since the epoch.
+fn handler_deadline(invocation_deadline: u64) -> Instant {
+    let remaining = (UNIX_EPOCH + Duration::from_millis(invocation_deadline))
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now()
+        + remaining.saturating_sub(Duration::from_secs(
+            app::current().settings.handler_kill_grace_period + DEADLINE_MARGIN,
+        ))
+}
+
+/// Handle the given S3 event records through the handler program,
+/// each paired with an identifier of its source. Returns the sources
+/// that contributed to batches that failed and may be retried, or
+/// that were deferred.
+async fn handle_records(records: Vec<(String, S3EventRecord)>, deadline: Instant) -> Unhandled {
+    let mut unhandled = Unhandled::default();
+    for batch in app::current()
+        .batch_events(records.into_iter(), client::current())
+        .await
+    {
+        match app::current()
+            .handle(&batch, client::current(), Some(deadline))
             .await
-            .with_context(|| format!("Failed to handle batch of records {:?}", &batch))?;
+        {
+            Ok(app::Outcome::Deferred(delay)) => {
+                info!(
+                    bucket = %batch.bucket,
+                    prefix = %batch.prefix,
+                    records = batch.records.len(),
+                    "Batch of records was deferred for {:?} seconds",
+                    delay.as_secs()
+                );
+                for source in batch.sources {
+                    let deferral = unhandled.deferred.entry(source).or_default();
+                    *deferral = (*deferral).max(delay);
+                }
+            }
+            Ok(outcome) if !outcome.is_retryable() => (),
+            Err(e) if !e.is_retryable() => {
+                error!(
+                    bucket = %batch.bucket,
+                    prefix = %batch.prefix,
+                    records = batch.records.len(),
+                    "Batch of records failed permanently and won't be retried: {}",
+                    e
+                );
+            }
+            result => {
+                warn!(
+                    bucket = %batch.bucket,
+                    prefix = %batch.prefix,
+                    records = batch.records.len(),
+                    "Batch of records failed and may be retried: {:?}",
+                    result
+                );
+                unhandled.failed.extend(batch.sources);
+            }
+        }
     }
-    Ok(())
+    unhandled
+}
+
+/// Look up the URL of the SQS queue with the given ARN.
+async fn queue_url(client: &Client, queue_arn: &str) -> Result<String> {
+    let (account, name) = match queue_arn.split(':').collect::<Vec<&str>>()[..] {
+        ["arn", _, "sqs", _, account, name] => (account, name),
+        _ => return Err(anyhow!("Invalid SQS queue ARN {:?}", queue_arn)),
+    };
+    client
+        .get_queue_url()
+        .queue_name(name)
+        .queue_owner_aws_account_id(account)
+        .send()
+        .await
+        .with_context(|| format!("Couldn't get the URL of SQS queue {:?}", queue_arn))?
+        .queue_url()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("SQS queue {:?} has no URL", queue_arn))
 }
 
-/// Run an AWS Lambda function that listens to SQS events containing
-/// batches of S3 events, that executes a handler command with files
-/// pulled from S3 according to the input events, and that uploads the
-/// command's results to S3 after it exits.
+/// Change the visibility timeout of the messages behind deferred
+/// batches, so that they're received again once their delay has
+/// passed. Messages also behind failed batches are left alone, and
+/// messages deferred more than `max_deferrals` times (as counted by
+/// their approximate receive count) are returned, to be dropped.
+async fn defer(
+    client: &Client,
+    messages: &BTreeMap<String, SqsMessage>,
+    unhandled: &Unhandled,
+    max_deferrals: u32,
+) -> BTreeSet<String> {
+    let mut exhausted = BTreeSet::new();
+    let mut deferred = BTreeMap::<&str, Vec<(String, String, Duration)>>::new();
+    for (message_id, delay) in &unhandled.deferred {
+        let message = match messages.get(message_id) {
+            Some(message) if !unhandled.failed.contains(message_id) => message,
+            _ => continue,
+        };
+        let receive_count = sqs::receive_count(
+            message
+                .attributes
+                .get("ApproximateReceiveCount")
+                .map(String::as_str),
+        );
+        if receive_count > max_deferrals {
+            error!(
+                message_id,
+                "SQS message was deferred more than {} times; it will be deleted", max_deferrals
+            );
+            exhausted.insert(message_id.clone());
+            continue;
+        }
+        if let (Some(queue_arn), Some(receipt_handle)) =
+            (&message.event_source_arn, &message.receipt_handle)
+        {
+            deferred.entry(queue_arn).or_default().push((
+                message_id.clone(),
+                receipt_handle.clone(),
+                *delay,
+            ));
+        }
+    }
+    for (queue_arn, messages) in deferred {
+        match queue_url(client, queue_arn).await {
+            Ok(queue_url) => {
+                info!(total = messages.len(), "Deferring SQS messages");
+                sqs::change_visibility(client, &queue_url, &messages).await;
+            }
+            Err(e) => warn!("Couldn't defer SQS messages: {:?}", e),
+        }
+    }
+    exhausted
+}
+
+/// Handle the S3 events held in a batch of SQS messages, and report
+/// the messages that contributed to failed or deferred batches.
+async fn handle_sqs_event(
+    sqs_event: SqsEvent,
+    deadline: Instant,
+    sqs_client: &Client,
+    max_deferrals: u32,
+) -> SqsBatchResponse {
+    let mut failed_message_ids = BTreeSet::new();
+    // Messages that can't be parsed are reported as failed, to become
+    // visible again
+    let mut records = Vec::new();
+    let mut messages = BTreeMap::new();
+    for mut message in sqs_event.records {
+        let message_id = message.message_id.clone().unwrap_or_default();
+        match event::parse_records(message.body.take().as_deref().unwrap_or_default()) {
+            Ok(message_records) => {
+                records.extend(
+                    message_records
+                        .into_iter()
+                        .map(|record| (message_id.clone(), record)),
+                );
+                messages.insert(message_id, message);
+            }
+            Err(e) => {
+                warn!(
+                    message_id,
+                    "Couldn't parse the body of SQS message: {:?}", e
+                );
+                failed_message_ids.insert(message_id);
+            }
+        }
+    }
+    // Deferred messages are reported as failed too, so that they're
+    // kept in the queue, but become visible again after their delay
+    let unhandled = handle_records(records, deadline).await;
+    let exhausted = defer(sqs_client, &messages, &unhandled, max_deferrals).await;
+    failed_message_ids.extend(
+        unhandled
+            .deferred
+            .into_keys()
+            .filter(|message_id| !exhausted.contains(message_id)),
+    );
+    failed_message_ids.extend(unhandled.failed);
+    SqsBatchResponse {
+        batch_item_failures: failed_message_ids
+            .into_iter()
+            .map(|item_identifier| BatchItemFailure { item_identifier })
+            .collect(),
+    }
+}
+
+/// Determine whether the given payload is a batch of SQS messages,
+/// as opposed to an S3 event delivered directly.
+fn is_sqs_event(payload: &Value) -> bool {
+    payload
+        .get("Records")
+        .and_then(Value::as_array)
+        .and_then(|records| records.first())
+        .and_then(|record| record.get("eventSource"))
+        .and_then(Value::as_str)
+        == Some("aws:sqs")
+}
+
+/// Handle the S3 events in the payload through the handler program.
+/// The payload may be a batch of SQS messages, in which case the
+/// messages that contributed to failed or deferred batches are
+/// reported as a partial batch response. Otherwise, the payload is
+/// expected to be an S3 event notification or an EventBridge event,
+/// and the invocation fails if any batch failed and may be retried,
+/// or was deferred.
+async fn function_handler(
+    lambda_event: LambdaEvent<Value>,
+    sqs_client: &Client,
+    max_deferrals: u32,
+) -> Result<Value> {
+    let deadline = handler_deadline(lambda_event.context.deadline);
+    if is_sqs_event(&lambda_event.payload) {
+        let sqs_event = serde_json::from_value::<SqsEvent>(lambda_event.payload)
+            .context("Failed to parse payload as a batch of SQS messages")?;
+        let response = handle_sqs_event(sqs_event, deadline, sqs_client, max_deferrals).await;
+        return serde_json::to_value(response).context("Failed to serialize batch response");
+    }
+    let request_id = lambda_event.context.request_id;
+    let records = event::parse_value(lambda_event.payload)?
+        .into_iter()
+        .map(|record| (request_id.clone(), record))
+        .collect();
+    let unhandled = handle_records(records, deadline).await;
+    if unhandled.failed.is_empty() && unhandled.deferred.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Err(anyhow!(
+            "Some batches of records failed or were deferred, and may be retried"
+        ))
+    }
+}
+
+/// Run an AWS Lambda function that listens to S3 events, either
+/// directly or through batches of SQS messages, that executes a
+/// handler command with files pulled from S3 according to the input
+/// events, and that uploads the command's results to S3 after it
+/// exits.
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -34,7 +281,16 @@ async fn main() -> Result<()> {
     app::init()?;
     client::init().await?;
 
-    run(service_fn(function_handler))
-        .await
-        .map_err(|e| anyhow!("{:?}", e))
+    let max_deferrals = var("SQS_MAX_DEFERRALS")
+        .unwrap_or(String::from("10"))
+        .parse::<u32>()
+        .context("SQS_MAX_DEFERRALS must be a non-negative number")?;
+    let sqs_client = Client::new(conf::aws_service_config().await);
+    let sqs_client = &sqs_client;
+
+    run(service_fn(move |lambda_event| {
+        function_handler(lambda_event, sqs_client, max_deferrals)
+    }))
+    .await
+    .map_err(|e| anyhow!("{:?}", e))
 }
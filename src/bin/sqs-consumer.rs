@@ -1,10 +1,24 @@
 use anyhow::{Context, Result};
-use aws_sdk_sqs::{types::DeleteMessageBatchRequestEntry, Client};
+use aws_sdk_sqs::{
+    types::{
+        DeleteMessageBatchRequestEntry, MessageAttributeValue, MessageSystemAttributeName,
+        QueueAttributeName,
+    },
+    Client,
+};
 use core::time::Duration;
-use s3_event_bridge::{app, client, conf};
-use std::env::var;
-use tokio::time::sleep;
-use tracing::{info, instrument, warn};
+use s3_event_bridge::{app, client, conf, error, event, sqs};
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    env::var,
+    sync::Arc,
+};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::{JoinError, JoinSet},
+    time::{sleep, timeout, Instant},
+};
+use tracing::{error, info, instrument, warn};
 
 /// The minimum time to wait between ticks, in milliseconds.
 const BASE_LAPSE_TIME: u64 = 300;
@@ -16,15 +30,131 @@ const BACKOFF_BASE: u64 = 2;
 /// the equivalent of 20 minutes.
 const MAX_SLEEP: u64 = 1200000;
 
+/// The message attribute holding the reason a message that couldn't
+/// be parsed was moved to the dead-letter queue.
+const PARSE_ERROR_ATTRIBUTE: &str = "ParseError";
+
+/// The message attribute holding the reason a message deferred too
+/// many times was moved to the dead-letter queue.
+const DEFERRAL_ERROR_ATTRIBUTE: &str = "DeferralError";
+
+/// The message attribute holding the reason a message behind a batch
+/// that failed permanently was moved to the dead-letter queue.
+const HANDLING_ERROR_ATTRIBUTE: &str = "HandlingError";
+
+/// Periodically extends the visibility timeout of every in-flight
+/// message. The lock over the receipt handles is held while the
+/// extensions are sent, so that messages stop being extended before
+/// they're deleted or deferred.
+async fn heartbeat(
+    client: Client,
+    queue_url: String,
+    receipt_handles: Arc<Mutex<BTreeMap<String, String>>>,
+    interval: u64,
+    visibility_timeout: Duration,
+) {
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        let receipt_handles = receipt_handles.lock().await;
+        if receipt_handles.is_empty() {
+            continue;
+        }
+        info!(
+            total = receipt_handles.len(),
+            "Extending visibility timeout of in-flight SQS messages"
+        );
+        let messages = receipt_handles
+            .iter()
+            .map(|(message_id, receipt_handle)| {
+                (
+                    message_id.clone(),
+                    receipt_handle.clone(),
+                    visibility_timeout,
+                )
+            })
+            .collect::<Vec<(String, String, Duration)>>();
+        sqs::change_visibility(&client, &queue_url, &messages).await;
+    }
+}
+
+/// An SQS message whose events are being handled. It's tracked until
+/// every batch it contributed events to is finished. Messages behind
+/// batches that can't succeed are marked as poison, with the
+/// attribute and reason to move them to the dead-letter queue with.
+struct InFlightMessage {
+    receipt_handle: String,
+    body: String,
+    receive_count: u32,
+    pending_batches: usize,
+    failed: bool,
+    deferral: Option<Duration>,
+    poison: Option<(&'static str, String)>,
+}
+
+/// A message to be moved to the dead-letter queue, along with the
+/// reason why, held as the given attribute.
+struct PoisonMessage {
+    message_id: String,
+    receipt_handle: String,
+    body: String,
+    attribute: &'static str,
+    reason: String,
+}
+
+/// An event batch held until no new events arrive for its bucket and
+/// prefix during the debounce period.
+struct PendingBatch {
+    batch: app::EventBatch,
+    last_event: Instant,
+}
+
+/// The result of handling a batch in the background.
+type BatchResult = (app::EventBatch, error::Result<app::Outcome>);
+
+/// Handle a batch of events in the background, once no other batch
+/// sharing its bucket and prefix is being handled and there's room
+/// in the worker pool.
+async fn handle_batch(
+    batch: app::EventBatch,
+    prefix_lock: Arc<Mutex<()>>,
+    pool: Arc<Semaphore>,
+) -> BatchResult {
+    let result = async {
+        let _prefix_guard = prefix_lock.lock().await;
+        let _permit = pool
+            .acquire()
+            .await
+            .context("The worker pool was closed")
+            .map_err(error::Error::Internal)?;
+        app::current().handle(&batch, client::current(), None).await
+    }
+    .await;
+    (batch, result)
+}
+
 /// Wrapper structure that executes successive SQS consumption cycles:
 /// receive messages, parse their contents, assemble event batches,
-/// invoke the handler, and finally delete messages.
+/// invoke the handler in a pool of workers, and finally delete the
+/// messages behind successful batches.
 struct SQSConsumer {
     queue_url: String,
+    dead_letter_queue_url: Option<String>,
     visibility_timeout: i32,
     max_number_of_messages: i32,
+    concurrency: usize,
+    debounce_period: u64,
+    max_deferrals: u32,
     client: Client,
     backoff: u32,
+    throttled: bool,
+    pool: Arc<Semaphore>,
+    prefix_locks: BTreeMap<(String, String), Arc<Mutex<()>>>,
+    in_flight: BTreeMap<String, InFlightMessage>,
+    receipt_handles: Arc<Mutex<BTreeMap<String, String>>>,
+    pending: BTreeMap<(String, String, bool), PendingBatch>,
+    running: JoinSet<BatchResult>,
+    deferred: Vec<(String, String, Duration)>,
+    poisoned: Vec<PoisonMessage>,
 }
 
 impl SQSConsumer {
@@ -44,85 +174,448 @@ impl SQSConsumer {
         self.backoff = self.backoff.saturating_add(1);
     }
 
-    /// Perform a single pass of the SQS consumption cycle.
-    #[instrument(skip(self))]
-    pub async fn tick(&mut self) -> () {
-        let command_result = self
+    /// Count a batch as pending for each of the given messages.
+    fn track_batch<'a, I>(&mut self, message_ids: I)
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        for message_id in message_ids {
+            if let Some(message) = self.in_flight.get_mut(message_id) {
+                message.pending_batches += 1;
+            }
+        }
+    }
+
+    /// Start handling a batch in the background.
+    fn start(&mut self, batch: app::EventBatch) {
+        let prefix_lock = self
+            .prefix_locks
+            .entry((batch.bucket.clone(), batch.prefix.clone()))
+            .or_default()
+            .clone();
+        self.running
+            .spawn(handle_batch(batch, prefix_lock, self.pool.clone()));
+    }
+
+    /// Hold a batch until its bucket and prefix have been quiet for
+    /// the debounce period, merging it with the batch already held
+    /// for them, if any. Messages received again while in flight
+    /// contribute no records, so the merged batches never share
+    /// sources.
+    fn hold(&mut self, batch: app::EventBatch) {
+        let key = (batch.bucket.clone(), batch.prefix.clone(), batch.removal);
+        self.track_batch(&batch.sources);
+        let mut pending = if let Some(mut pending) = self.pending.remove(&key) {
+            pending.batch.merge(batch);
+            pending
+        } else {
+            PendingBatch {
+                batch,
+                last_event: Instant::now(),
+            }
+        };
+        pending.last_event = Instant::now();
+        self.pending.insert(key, pending);
+    }
+
+    /// Start handling the held batches whose bucket and prefix have
+    /// been quiet for the debounce period.
+    fn release(&mut self) {
+        let debounce_period = Duration::from_secs(self.debounce_period);
+        let quiet = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.last_event.elapsed() >= debounce_period)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<(String, String, bool)>>();
+        for key in quiet {
+            if let Some(pending) = self.pending.remove(&key) {
+                info!(
+                    bucket = pending.batch.bucket,
+                    prefix = pending.batch.prefix,
+                    total = pending.batch.records.len(),
+                    "Releasing debounced batch"
+                );
+                self.start(pending.batch);
+            }
+        }
+    }
+
+    /// Move the given messages to the dead-letter queue, attaching
+    /// the reason to each of them as its attribute. Returns the
+    /// messages that were moved, and can be deleted.
+    async fn quarantine(&self, poison: Vec<PoisonMessage>) -> Vec<(String, String)> {
+        let dead_letter_queue_url = if let Some(url) = &self.dead_letter_queue_url {
+            url
+        } else {
+            return Vec::new();
+        };
+        let mut moved = Vec::new();
+        for message in poison {
+            let message_id = message.message_id;
+            let result = self
+                .client
+                .send_message()
+                .queue_url(dead_letter_queue_url)
+                .message_body(message.body)
+                .message_attributes(
+                    message.attribute,
+                    MessageAttributeValue::builder()
+                        .data_type("String")
+                        .string_value(message.reason)
+                        .build(),
+                )
+                .send()
+                .await;
+            match result {
+                Ok(_) => {
+                    info!(message_id, "Moved SQS message to the dead-letter queue");
+                    moved.push((message_id, message.receipt_handle));
+                }
+                Err(e) => warn!(
+                    message_id,
+                    "Couldn't move SQS message to the dead-letter queue: {:?}", e
+                ),
+            }
+        }
+        moved
+    }
+
+    /// Receive messages, assemble event batches and start handling
+    /// them in the background. Returns the messages that can be
+    /// deleted right away, since they contributed to no batch or were
+    /// moved to the dead-letter queue.
+    async fn receive(&mut self) -> Result<Vec<(String, String)>> {
+        let result = self
             .client
             .receive_message()
             .queue_url(&self.queue_url)
             .visibility_timeout(self.visibility_timeout)
             .max_number_of_messages(self.max_number_of_messages)
-            .wait_time_seconds(30)
+            .attribute_names(QueueAttributeName::All)
+            .wait_time_seconds(if self.running.is_empty() && self.pending.is_empty() {
+                30
+            } else {
+                1
+            })
             .send()
-            .await;
-        if let Err(e) = command_result {
-            warn!("Error while consuming messages from SQS queue: {:?}", e);
-            return self.fail().await;
+            .await
+            .context("Error while consuming messages from SQS queue")?;
+        let messages = result.messages().unwrap_or_default();
+
+        // Messages that can't be parsed are moved to the dead-letter
+        // queue if there's one, or otherwise kept to become visible
+        // again
+        let mut records = Vec::new();
+        let mut received = Vec::new();
+        let mut poison = Vec::new();
+        for message in messages {
+            let message_id = message.message_id().unwrap_or_default().to_string();
+            match event::parse_records(message.body().unwrap_or_default()) {
+                Ok(message_records) => {
+                    let receipt_handle = message.receipt_handle().unwrap_or_default().to_string();
+                    let receive_count = sqs::receive_count(
+                        message
+                            .attributes()
+                            .and_then(|attributes| {
+                                attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount)
+                            })
+                            .map(String::as_str),
+                    );
+                    received.push((message_id.clone(), receipt_handle.clone()));
+                    // A message received again while still in flight
+                    // (e.g. held longer than its visibility timeout)
+                    // keeps its pending batches, which already hold
+                    // its records, and only its latest receipt handle
+                    // is valid
+                    match self.in_flight.entry(message_id.clone()) {
+                        Entry::Occupied(mut entry) => {
+                            let message = entry.get_mut();
+                            message.receipt_handle = receipt_handle;
+                            message.receive_count = receive_count;
+                        }
+                        Entry::Vacant(entry) => {
+                            records.extend(
+                                message_records
+                                    .into_iter()
+                                    .map(|record| (message_id.clone(), record)),
+                            );
+                            entry.insert(InFlightMessage {
+                                receipt_handle,
+                                body: message.body().unwrap_or_default().to_string(),
+                                receive_count,
+                                pending_batches: 0,
+                                failed: false,
+                                deferral: None,
+                                poison: None,
+                            });
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        message_id,
+                        "Couldn't parse the body of SQS message: {:?}", e
+                    );
+                    poison.push(PoisonMessage {
+                        message_id,
+                        receipt_handle: message.receipt_handle().unwrap_or_default().to_string(),
+                        body: message.body().unwrap_or_default().to_string(),
+                        attribute: PARSE_ERROR_ATTRIBUTE,
+                        reason: format!("{:#}", e),
+                    });
+                }
+            }
         }
+        self.receipt_handles.lock().await.extend(received);
+        let quarantined = self.quarantine(poison).await;
 
-        let result = command_result.unwrap();
-        let messages = result.messages().unwrap_or_default();
-        let mut handling_error = None;
-
-        for batch in app::current().batch_events(
-            messages
-                .iter()
-                .filter_map(|message| message.body())
-                .filter_map(|body| {
-                    serde_json::from_str(body)
-                        .map_err(|e| {
-                            warn!("Couldn't parse the body of SQS message: {:?}", e);
-                            e
-                        })
-                        .ok()
+        for batch in app::current()
+            .batch_events(records.into_iter(), client::current())
+            .await
+        {
+            if self.debounce_period > 0 {
+                self.hold(batch);
+            } else {
+                self.track_batch(&batch.sources);
+                self.start(batch);
+            }
+        }
+
+        let mut deletable = self.take_finished_messages().await;
+        deletable.extend(quarantined);
+        Ok(deletable)
+    }
+
+    /// Record the result of a finished batch.
+    fn finish(&mut self, finished: Result<BatchResult, JoinError>) {
+        match finished {
+            Ok((batch, Ok(app::Outcome::Deferred(delay)))) => {
+                // Messages are received once more after each deferral,
+                // although retries and lapsed visibility timeouts add
+                // to the count too
+                let exceeded = batch.sources.iter().any(|message_id| {
+                    self.in_flight
+                        .get(message_id)
+                        .is_some_and(|message| message.receive_count > self.max_deferrals)
+                });
+                if exceeded {
+                    error!(
+                        bucket = %batch.bucket,
+                        prefix = %batch.prefix,
+                        records = batch.records.len(),
+                        "Batch of records was deferred more than {} times; \
+                         its SQS messages will be moved to the dead-letter queue, \
+                         if there's one, or deleted otherwise",
+                        self.max_deferrals
+                    );
+                } else {
+                    info!(
+                        bucket = %batch.bucket,
+                        prefix = %batch.prefix,
+                        records = batch.records.len(),
+                        "Batch of records was deferred; \
+                         its SQS messages will become visible again in {:?} seconds",
+                        delay.as_secs()
+                    );
+                }
+                for message_id in &batch.sources {
+                    if let Some(message) = self.in_flight.get_mut(message_id) {
+                        message.pending_batches = message.pending_batches.saturating_sub(1);
+                        if exceeded {
+                            message.poison.get_or_insert_with(|| {
+                                (
+                                    DEFERRAL_ERROR_ATTRIBUTE,
+                                    format!("Deferred more than {} times", self.max_deferrals),
+                                )
+                            });
+                        } else {
+                            message.deferral = message.deferral.max(Some(delay));
+                        }
+                    }
+                }
+            }
+            Ok((batch, Ok(outcome))) if !outcome.is_retryable() => {
+                for message_id in &batch.sources {
+                    if let Some(message) = self.in_flight.get_mut(message_id) {
+                        message.pending_batches = message.pending_batches.saturating_sub(1);
+                    }
+                }
+            }
+            Ok((batch, Err(e))) if !e.is_retryable() => {
+                error!(
+                    bucket = %batch.bucket,
+                    prefix = %batch.prefix,
+                    records = batch.records.len(),
+                    "Batch of records failed permanently; \
+                     its SQS messages will be moved to the dead-letter queue, \
+                     if there's one, or deleted otherwise: {}",
+                    e
+                );
+                for message_id in &batch.sources {
+                    if let Some(message) = self.in_flight.get_mut(message_id) {
+                        message.pending_batches = message.pending_batches.saturating_sub(1);
+                        message
+                            .poison
+                            .get_or_insert_with(|| (HANDLING_ERROR_ATTRIBUTE, e.to_string()));
+                    }
+                }
+            }
+            Ok((batch, result)) => {
+                if let Err(error::Error::Throttled(_)) = &result {
+                    self.throttled = true;
+                }
+                warn!(
+                    bucket = %batch.bucket,
+                    prefix = %batch.prefix,
+                    records = batch.records.len(),
+                    "Batch of records failed and may be retried; \
+                     its SQS messages won't be deleted: {:?}",
+                    result
+                );
+                for message_id in &batch.sources {
+                    if let Some(message) = self.in_flight.get_mut(message_id) {
+                        message.pending_batches = message.pending_batches.saturating_sub(1);
+                        message.failed = true;
+                    }
+                }
+            }
+            Err(e) => warn!("Couldn't join batch handling task: {:?}", e),
+        }
+        self.prefix_locks
+            .retain(|_, prefix_lock| Arc::strong_count(prefix_lock) > 1);
+    }
+
+    /// Stop tracking the messages without pending batches, and return
+    /// the ones that can be deleted. Deferred messages are set apart,
+    /// to have their visibility timeout changed instead, and so are
+    /// poison messages (deferred too many times, or behind batches
+    /// that failed permanently), to be moved to the dead-letter queue
+    /// if there's one.
+    async fn take_finished_messages(&mut self) -> Vec<(String, String)> {
+        let finished_message_ids = self
+            .in_flight
+            .iter()
+            .filter(|(_, message)| message.pending_batches == 0)
+            .map(|(message_id, _)| message_id.clone())
+            .collect::<Vec<String>>();
+        let mut receipt_handles = self.receipt_handles.lock().await;
+        let mut deletable = Vec::new();
+        for message_id in finished_message_ids {
+            receipt_handles.remove(&message_id);
+            match self.in_flight.remove(&message_id) {
+                Some(message) if message.failed => (),
+                Some(message)
+                    if message.poison.is_some() && self.dead_letter_queue_url.is_none() =>
+                {
+                    deletable.push((message_id, message.receipt_handle))
+                }
+                Some(InFlightMessage {
+                    receipt_handle,
+                    body,
+                    poison: Some((attribute, reason)),
+                    ..
+                }) => self.poisoned.push(PoisonMessage {
+                    message_id,
+                    receipt_handle,
+                    body,
+                    attribute,
+                    reason,
                 }),
-        ) {
-            let handle_result = app::current().handle(&batch, client::current()).await;
-            if let Err(e) = handle_result {
-                handling_error = Some(e);
+                Some(InFlightMessage {
+                    receipt_handle,
+                    deferral: Some(delay),
+                    ..
+                }) => self.deferred.push((message_id, receipt_handle, delay)),
+                Some(message) => deletable.push((message_id, message.receipt_handle)),
+                None => (),
             }
         }
-        if let Some(e) = handling_error {
-            warn!(
-                "Error encountered while handling events; SQS messages won't be deleted: {:?}",
-                e
-            );
-            return self.pass().await;
+        deletable
+    }
+
+    /// Change the visibility timeout of the deferred messages, so that
+    /// they're received again once their delay has passed.
+    async fn defer(&mut self) {
+        let deferred = std::mem::take(&mut self.deferred);
+        if !deferred.is_empty() {
+            info!(total = deferred.len(), "Deferring SQS messages");
+            sqs::change_visibility(&self.client, &self.queue_url, &deferred).await;
         }
-        if messages.is_empty() {
-            return self.pass().await;
+    }
+
+    /// Delete the given messages from the queue.
+    async fn delete(&self, messages: &[(String, String)]) -> Result<()> {
+        for chunk in messages.chunks(sqs::MAX_BATCH_SIZE) {
+            info!(total = chunk.len(), "Deleting SQS messages");
+            let result = self
+                .client
+                .delete_message_batch()
+                .queue_url(&self.queue_url)
+                .set_entries(Some(
+                    chunk
+                        .iter()
+                        .map(|(message_id, receipt_handle)| {
+                            DeleteMessageBatchRequestEntry::builder()
+                                .id(message_id)
+                                .receipt_handle(receipt_handle)
+                                .build()
+                        })
+                        .collect(),
+                ))
+                .send()
+                .await
+                .context("Couldn't delete SQS messages")?;
+            if !result.failed().unwrap_or_default().is_empty() {
+                let failed = result.failed().unwrap_or_default().len();
+                let total = chunk.len();
+                warn!(
+                    "Couldn't delete some SQS messages: {:?} out of {:?} weren't deleted",
+                    failed, total
+                );
+            }
         }
-        info!("Deleting SQS messages");
-        let delete_command_result = self
-            .client
-            .delete_message_batch()
-            .queue_url(&self.queue_url)
-            .set_entries(Some(
-                messages
-                    .iter()
-                    .map(|message| {
-                        DeleteMessageBatchRequestEntry::builder()
-                            .set_id(message.message_id().map(String::from))
-                            .set_receipt_handle(message.receipt_handle().map(String::from))
-                            .build()
-                    })
-                    .collect(),
-            ))
-            .send()
-            .await;
-        if let Err(e) = delete_command_result {
-            warn!("Couldn't delete SQS messages: {:?}", e);
+        Ok(())
+    }
+
+    /// Perform a single pass of the SQS consumption cycle.
+    #[instrument(skip(self))]
+    pub async fn tick(&mut self) -> () {
+        self.release();
+        let mut deletable = Vec::new();
+        let mut receive_error = None;
+        if self.running.len() < self.concurrency {
+            match self.receive().await {
+                Ok(messages) => deletable.extend(messages),
+                Err(e) => receive_error = Some(e),
+            }
+        } else if let Some(finished) = self.running.join_next().await {
+            self.finish(finished);
+        }
+        // Collect every other finished batch without waiting
+        while let Ok(Some(finished)) = timeout(Duration::ZERO, self.running.join_next()).await {
+            self.finish(finished);
+        }
+        deletable.extend(self.take_finished_messages().await);
+        // Deferred messages become visible again anyway once their
+        // current visibility timeout expires, so failures are only
+        // logged
+        self.defer().await;
+        let poisoned = std::mem::take(&mut self.poisoned);
+        deletable.extend(self.quarantine(poisoned).await);
+
+        if let Err(e) = self.delete(&deletable).await {
+            warn!("{:?}", e);
+            return self.fail().await;
+        }
+        if let Some(e) = receive_error {
+            warn!("{:?}", e);
             return self.fail().await;
         }
-        let result = delete_command_result.unwrap();
-        if !result.failed().unwrap_or_default().is_empty() {
-            let failed = result.failed().unwrap_or_default().len();
-            let total = messages.len();
-            warn!(
-                "Couldn't delete some SQS messages: {:?} out of {:?} weren't deleted",
-                failed, total
-            );
+        // Slow down if S3 is throttling requests
+        if self.throttled {
+            self.throttled = false;
+            return self.fail().await;
         }
         self.pass().await;
     }
@@ -141,24 +634,66 @@ async fn main() -> Result<()> {
     client::init().await?;
 
     let queue_url = var("SQS_QUEUE_URL").context("SQS_QUEUE_URL is required")?;
+    let dead_letter_queue_url = var("SQS_DEAD_LETTER_QUEUE_URL").ok();
     let visibility_timeout = var("SQS_VISIBILITY_TIMEOUT")
         .unwrap_or(String::from("30"))
         .parse::<i32>()
         .context("SQS_VISIBILITY_TIMEOUT must be a number")?;
+    let heartbeat_interval = var("SQS_HEARTBEAT_INTERVAL")
+        .unwrap_or((visibility_timeout / 2).max(1).to_string())
+        .parse::<u64>()
+        .context("SQS_HEARTBEAT_INTERVAL must be a non-negative number")?;
     let max_number_of_messages = var("SQS_MAX_NUMBER_OF_MESSAGES")
         .unwrap_or(String::from("1"))
         .parse::<i32>()
         .context("SQS_MAX_NUMBER_OF_MESSAGES must be a number")?;
+    let concurrency = var("SQS_CONCURRENCY")
+        .unwrap_or(String::from("1"))
+        .parse::<usize>()
+        .context("SQS_CONCURRENCY must be a positive number")?
+        .max(1);
+    let debounce_period = var("SQS_DEBOUNCE_PERIOD")
+        .unwrap_or(String::from("0"))
+        .parse::<u64>()
+        .context("SQS_DEBOUNCE_PERIOD must be a non-negative number")?;
+    let max_deferrals = var("SQS_MAX_DEFERRALS")
+        .unwrap_or(String::from("10"))
+        .parse::<u32>()
+        .context("SQS_MAX_DEFERRALS must be a non-negative number")?;
     let sqs_client = Client::new(conf::aws_service_config().await);
 
     let mut consumer = SQSConsumer {
         queue_url,
+        dead_letter_queue_url,
         visibility_timeout,
         max_number_of_messages,
+        concurrency,
+        debounce_period,
+        max_deferrals,
         client: sqs_client,
         backoff: 0,
+        throttled: false,
+        pool: Arc::new(Semaphore::new(concurrency)),
+        prefix_locks: BTreeMap::new(),
+        in_flight: BTreeMap::new(),
+        receipt_handles: Arc::new(Mutex::new(BTreeMap::new())),
+        pending: BTreeMap::new(),
+        running: JoinSet::new(),
+        deferred: Vec::new(),
+        poisoned: Vec::new(),
     };
 
+    // Keep every in-flight message invisible while it's handled
+    if heartbeat_interval > 0 {
+        tokio::spawn(heartbeat(
+            consumer.client.clone(),
+            consumer.queue_url.clone(),
+            consumer.receipt_handles.clone(),
+            heartbeat_interval,
+            Duration::from_secs(visibility_timeout.max(0) as u64),
+        ));
+    }
+
     // Listen for abort signals
     let (stop_processing, mut should_stop) = tokio::sync::oneshot::channel();
     tokio::spawn(async {
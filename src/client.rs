@@ -1,8 +1,15 @@
 //! Defines the global S3 client.
 
 use crate::conf::aws_service_config;
-use anyhow::{anyhow, Context, Result};
-use aws_sdk_s3::{primitives::ByteStream, types::Object, Client};
+use crate::error::{Error, Result};
+use anyhow::anyhow;
+use aws_sdk_s3::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::head_object::HeadObjectOutput,
+    primitives::ByteStream,
+    types::{Delete, Object, ObjectIdentifier},
+    Client,
+};
 use once_cell::sync::OnceCell;
 use std::path::Path;
 use tokio::{
@@ -10,6 +17,58 @@ use tokio::{
     io::copy,
 };
 
+/// The user-defined metadata key used to mark objects uploaded by the
+/// event bridge.
+pub const MARKER_METADATA_KEY: &str = "s3-event-bridge-marker";
+
+/// Classify an S3 failure given its error code and HTTP status.
+fn classify(code: Option<&str>, status: Option<u16>, error: anyhow::Error) -> Error {
+    match (code, status) {
+        (
+            Some(
+                "SlowDown"
+                | "Throttling"
+                | "ThrottlingException"
+                | "RequestLimitExceeded"
+                | "TooManyRequests",
+            ),
+            _,
+        )
+        | (_, Some(429 | 503)) => Error::Throttled(error),
+        (Some("NoSuchKey" | "NoSuchBucket" | "NotFound"), _) | (_, Some(404)) => {
+            Error::NotFound(error)
+        }
+        (Some("AccessDenied" | "Forbidden"), _) | (_, Some(403)) => Error::AccessDenied(error),
+        _ => Error::Storage(error),
+    }
+}
+
+/// Classify an error returned by the S3 client, wrapping it with the
+/// given context.
+fn classify_sdk_error<E>(error: SdkError<E>, context: String) -> Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let code = error.code().map(String::from);
+    let status = error
+        .raw_response()
+        .map(|response| response.status().as_u16());
+    classify(
+        code.as_deref(),
+        status,
+        anyhow::Error::new(error).context(context),
+    )
+}
+
+/// Classify a local file system error, wrapping it with the given
+/// context.
+fn local_io_error<E>(error: E, context: String) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::LocalIo(anyhow::Error::new(error).context(context))
+}
+
 /// Lists all keys found in a bucket under a given prefix. Returns a
 /// page of keys and a token that can be used for a subsequent fetch.
 pub async fn list_keys(
@@ -22,13 +81,16 @@ pub async fn list_keys(
     if let Some(continuation_token) = next {
         operation = operation.continuation_token(continuation_token)
     }
-    let response = operation.send().await.with_context(|| {
-        format!(
-            "Failed to list keys under {:?} in bucket {:?} \
-             using {} continuation token",
-            prefix,
-            bucket,
-            if next.is_some() { "a" } else { "no" }
+    let response = operation.send().await.map_err(|e| {
+        classify_sdk_error(
+            e,
+            format!(
+                "Failed to list keys under {:?} in bucket {:?} \
+                 using {} continuation token",
+                prefix,
+                bucket,
+                if next.is_some() { "a" } else { "no" }
+            ),
         )
     })?;
     Ok((
@@ -41,10 +103,13 @@ pub async fn list_keys(
 pub async fn download(client: &Client, bucket: &str, key: &str, path: &Path) -> Result<()> {
     // Ensure the directory structure exists
     if let Some(parent) = path.parent() {
-        create_dir_all(parent).await.with_context(|| {
-            format!(
-                "Failed to prepare local directory {:?} for object {:?}",
-                parent, key
+        create_dir_all(parent).await.map_err(|e| {
+            local_io_error(
+                e,
+                format!(
+                    "Failed to prepare local directory {:?} for object {:?}",
+                    parent, key
+                ),
             )
         })?;
     }
@@ -54,59 +119,194 @@ pub async fn download(client: &Client, bucket: &str, key: &str, path: &Path) ->
         .key(key)
         .send()
         .await
-        .with_context(|| {
-            format!(
-                "Failed to download object {:?} from bucket {:?}",
-                key, bucket
-            )
+        .map_err(|e| {
+            match classify_sdk_error(
+                e,
+                format!(
+                    "Failed to download object {:?} from bucket {:?}",
+                    key, bucket
+                ),
+            ) {
+                // Objects are downloaded after being listed, so a
+                // missing one was removed in the meantime, and a
+                // retry will list the prefix anew
+                Error::NotFound(e) => Error::Storage(e),
+                e => e,
+            }
         })?
         .body
         .into_async_read();
-    let mut file = File::create(path).await.with_context(|| {
-        format!(
-            "Failed to create local file {:?} to hold remote object {:?} from bucket {:?}",
-            path, key, bucket
+    let mut file = File::create(path).await.map_err(|e| {
+        local_io_error(
+            e,
+            format!(
+                "Failed to create local file {:?} to hold remote object {:?} from bucket {:?}",
+                path, key, bucket
+            ),
         )
     })?;
-    copy(&mut body, &mut file).await.with_context(|| {
-        format!(
+    // The body is streamed while copying, so failures could come
+    // from either end
+    copy(&mut body, &mut file).await.map_err(|e| {
+        Error::Storage(anyhow::Error::new(e).context(format!(
             "Failed to save the contents of remote object {:?} from bucket {:?} \
              into local file {:?}",
             key, bucket, path
-        )
+        )))
     })?;
     Ok(())
 }
 
-/// Uploads a single object to storage.
-pub async fn upload(client: &Client, bucket: &str, path: &Path, key: &str) -> Result<()> {
-    let body = ByteStream::from_path(path).await.with_context(|| {
-        format!(
-            "Failed to load contents of local file {:?} for upload",
-            path
+/// Fetches the metadata of a single object from storage. Returns
+/// `None` if the object doesn't exist.
+pub async fn head(client: &Client, bucket: &str, key: &str) -> Result<Option<HeadObjectOutput>> {
+    match client.head_object().bucket(bucket).key(key).send().await {
+        Ok(output) => Ok(Some(output)),
+        Err(e) => {
+            if matches!(&e, SdkError::ServiceError(context) if context.err().is_not_found()) {
+                Ok(None)
+            } else {
+                Err(classify_sdk_error(
+                    e,
+                    format!(
+                        "Failed to fetch metadata of object {:?} from bucket {:?}",
+                        key, bucket
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+/// Uploads a single object to storage, marking it with the given
+/// marker as user-defined metadata.
+pub async fn upload(
+    client: &Client,
+    bucket: &str,
+    path: &Path,
+    key: &str,
+    marker: &str,
+) -> Result<()> {
+    let body = ByteStream::from_path(path).await.map_err(|e| {
+        local_io_error(
+            e,
+            format!(
+                "Failed to load contents of local file {:?} for upload",
+                path
+            ),
         )
     })?;
     client
         .put_object()
         .bucket(bucket)
         .key(key)
+        .metadata(MARKER_METADATA_KEY, marker)
         .body(body)
         .send()
         .await
-        .with_context(|| {
-            format!(
-                "Failed to upload local file {:?} to remote object {:?} in bucket {:?}",
-                path, key, bucket
+        .map_err(|e| {
+            classify_sdk_error(
+                e,
+                format!(
+                    "Failed to upload local file {:?} to remote object {:?} in bucket {:?}",
+                    path, key, bucket
+                ),
+            )
+        })?;
+    Ok(())
+}
+
+/// Uploads the given contents as a single object to storage, marking
+/// it with the given marker as user-defined metadata.
+pub async fn upload_contents(
+    client: &Client,
+    bucket: &str,
+    contents: Vec<u8>,
+    content_type: &str,
+    key: &str,
+    marker: &str,
+) -> Result<()> {
+    client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .metadata(MARKER_METADATA_KEY, marker)
+        .content_type(content_type)
+        .body(ByteStream::from(contents))
+        .send()
+        .await
+        .map_err(|e| {
+            classify_sdk_error(
+                e,
+                format!(
+                    "Failed to upload contents to remote object {:?} in bucket {:?}",
+                    key, bucket
+                ),
             )
         })?;
     Ok(())
 }
 
+/// The maximum amount of keys accepted by a single DeleteObjects
+/// request.
+const MAX_DELETE_BATCH_SIZE: usize = 1000;
+
+/// Deletes the given objects from storage, in batches of at most
+/// [`MAX_DELETE_BATCH_SIZE`] keys.
+pub async fn delete(client: &Client, bucket: &str, keys: &[String]) -> Result<()> {
+    for chunk in keys.chunks(MAX_DELETE_BATCH_SIZE) {
+        let response = client
+            .delete_objects()
+            .bucket(bucket)
+            .delete(
+                Delete::builder()
+                    .set_objects(Some(
+                        chunk
+                            .iter()
+                            .map(|key| ObjectIdentifier::builder().key(key).build())
+                            .collect(),
+                    ))
+                    .quiet(true)
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| {
+                classify_sdk_error(
+                    e,
+                    format!(
+                        "Failed to delete {} objects from bucket {:?}",
+                        chunk.len(),
+                        bucket
+                    ),
+                )
+            })?;
+        let errors = response.errors().unwrap_or_default();
+        if let Some(error) = errors.first() {
+            return Err(classify(
+                error.code(),
+                None,
+                anyhow!(
+                    "Failed to delete {} out of {} objects from bucket {:?}; \
+                     first failure was object {:?}: {} ({})",
+                    errors.len(),
+                    chunk.len(),
+                    bucket,
+                    error.key().unwrap_or_default(),
+                    error.message().unwrap_or_default(),
+                    error.code().unwrap_or_default()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// Global S3 client instance.
 static CURRENT: OnceCell<Client> = OnceCell::new();
 
 /// Initialize the global S3 client.
-pub async fn init() -> Result<()> {
+pub async fn init() -> anyhow::Result<()> {
     let s3_config = aws_service_config().await;
     let client = Client::new(s3_config);
     CURRENT
@@ -4,6 +4,21 @@ use once_cell::sync::OnceCell;
 use serde::Deserialize;
 use std::env;
 
+/// Default `upload_marker` value.
+fn default_upload_marker() -> String {
+    String::from("s3-event-bridge")
+}
+
+/// Default `handler_kill_grace_period` value.
+fn default_handler_kill_grace_period() -> u64 {
+    10
+}
+
+/// Default `handler_success_exit_codes` value.
+fn default_handler_success_exit_codes() -> Vec<i32> {
+    vec![0]
+}
+
 /// Default `root_folder_var` value.
 fn default_root_folder_var() -> String {
     String::from("ROOT_FOLDER")
@@ -19,6 +34,16 @@ fn default_key_prefix_var() -> String {
     String::from("KEY_PREFIX")
 }
 
+/// Default `events_file_var` value.
+fn default_events_file_var() -> String {
+    String::from("EVENTS_FILE")
+}
+
+/// Default `filter_result_var` value.
+fn default_filter_result_var() -> String {
+    String::from("FILTER_RESULT")
+}
+
 /// The event bridge is configured to pull files from S3, execute a
 /// command, and push resulting files to S3. The configuration must be
 /// given as environment variables.
@@ -29,6 +54,23 @@ pub struct Settings {
     #[serde(default)]
     pub match_key: Option<String>,
 
+    /// Defines a filter to select only events with matching names
+    /// (e.g. `ObjectCreated:*`). Use globs, where `*` matches any
+    /// sequence of characters. Omitting this will make it match any
+    /// event.
+    #[serde(default)]
+    pub match_event_names: Vec<String>,
+
+    /// Defines a shell command to handle removal events
+    /// (`ObjectRemoved:*`) instead of the main handler command, so
+    /// that outputs derived from removed objects can be cleaned
+    /// up. Removal events are grouped apart from other events, and
+    /// the command gets the same environment as the main handler
+    /// command. If omitted, removal events are handled by the main
+    /// handler command.
+    #[serde(default)]
+    pub removal_handler_command: Option<String>,
+
     /// Defines the folder to pull from S3 given an event key. It
     /// counts the parent directories from the key, where `0` means
     /// the containing folder. If given a value greater than the
@@ -38,6 +80,25 @@ pub struct Settings {
     #[serde(default)]
     pub pull_parent_dirs: i32,
 
+    /// Defines the prefix to pull from S3 given an event key, as a
+    /// replacement template over the capture groups of `match_key`
+    /// (e.g. `${tenant}/${date}/`, with groups capturing
+    /// `tenant=...` and `date=...`), following the regex crate's
+    /// replacement syntax. Every group referenced must exist, and
+    /// events whose key doesn't match all of them, or that result in
+    /// an empty prefix, are ignored. If given, `pull_parent_dirs` is
+    /// ignored.
+    #[serde(default)]
+    pub batch_key: Option<String>,
+
+    /// Defines the prefix to pull from S3 given an event, as a jq
+    /// expression that receives an object with the event `record`
+    /// and the named `captures` of `match_key`, and returns a
+    /// string. If given, `pull_parent_dirs` is ignored. Can't be used
+    /// together with `batch_key`.
+    #[serde(default)]
+    pub batch_key_expr: Option<String>,
+
     /// Defines filters to pull only matching keys, after having
     /// selected a folder to pull. This limits the files pulled to
     /// those matching any of the expressions. If omitted or empty, it
@@ -50,26 +111,141 @@ pub struct Settings {
 
     /// Defines a jq expression to run against the set of objects to
     /// be pulled which, if defined and returning `false`, will skip
-    /// execution.
+    /// execution. It may instead return an object of directives:
+    /// `run` (whether to execute), `defer` (seconds after which the
+    /// events should be handled again), `env` (additional variables
+    /// for the handler command) and `pull` (the keys to pull,
+    /// replacing `pull_match_keys`).
     #[serde(default)]
     pub execution_filter_expr: Option<String>,
 
     /// Defines a file containing a jq expression to run against the
-    /// set of objects to be pulled which, if defined and returning
-    /// `false`, will skip execution.
+    /// set of objects to be pulled, with the same semantics as
+    /// `execution_filter_expr`.
     #[serde(default)]
     pub execution_filter_file: Option<String>,
 
+    /// Defines whether each result of the execution filter should
+    /// cause a separate execution of the handler command, each with
+    /// its own download of the objects. The result is passed as JSON
+    /// to the handler command, through the variable named by
+    /// `filter_result_var`. If the filter produces no results, the
+    /// handler command isn't executed.
+    #[serde(default)]
+    pub execution_filter_fan_out: bool,
+
     /// Defines a bucket to receive the outputs. If omitted, it will
     /// be the same bucket as the one in the triggering event.
     #[serde(default)]
     pub target_bucket: Option<String>,
 
+    /// Defines a key prefix to place the outputs under. If omitted,
+    /// outputs will be placed under the same prefix used to pull
+    /// files from S3. The prefix may contain placeholders in braces
+    /// (e.g. `processed/{year}/{name}/`), which get replaced by the
+    /// named capture groups of `match_key`, the bucket (`{bucket}`),
+    /// the prefix used to pull files (`{prefix}`) or a component of
+    /// the event time (`{year}`, `{month}`, `{day}`, `{hour}`,
+    /// `{minute}` and `{second}`).
+    #[serde(default)]
+    pub target_prefix: Option<String>,
+
+    /// Defines the marker attached as user-defined metadata to every
+    /// object uploaded by the event bridge.
+    #[serde(default = "default_upload_marker")]
+    pub upload_marker: String,
+
+    /// Defines whether events caused by objects uploaded with the
+    /// same `upload_marker` should be ignored. This prevents the
+    /// event bridge from triggering itself when writing outputs to
+    /// the bucket it receives events from, at the cost of fetching
+    /// the metadata of each object before grouping events.
+    #[serde(default)]
+    pub skip_own_uploads: bool,
+
+    /// Defines whether files pulled from S3 and removed by the handler
+    /// command should also be removed from S3. If enabled, the
+    /// objects corresponding to the removed files are deleted from
+    /// the bucket they were pulled from, after outputs are uploaded.
+    /// The deletions cause removal events of their own, so
+    /// `match_event_names` must exclude them (e.g. `ObjectCreated:*`)
+    /// when this is enabled.
+    #[serde(default)]
+    pub propagate_deletions: bool,
+
+    /// Defines the maximum amount of seconds the handler command is
+    /// allowed to run. When exceeded, the handler command's process
+    /// group is sent a SIGTERM, followed by a SIGKILL after
+    /// `handler_kill_grace_period` seconds, and the batch is
+    /// considered failed. If omitted, the handler command may run
+    /// indefinitely, unless the caller imposes a deadline (e.g. the
+    /// AWS Lambda invocation deadline).
+    #[serde(default)]
+    pub handler_timeout: Option<u64>,
+
+    /// Defines the amount of seconds to wait for the handler command
+    /// to exit after being sent a SIGTERM, before sending a SIGKILL.
+    #[serde(default = "default_handler_kill_grace_period")]
+    pub handler_kill_grace_period: u64,
+
+    /// Defines the exit codes of the handler command that are
+    /// considered successful. Outputs are only uploaded after a
+    /// successful execution.
+    #[serde(default = "default_handler_success_exit_codes")]
+    pub handler_success_exit_codes: Vec<i32>,
+
+    /// Defines the exit codes of the handler command that are
+    /// considered permanent failures, meaning that the events that
+    /// caused the execution shouldn't be retried. Exit codes that
+    /// aren't listed either here or in `handler_success_exit_codes`
+    /// (and handler commands killed by a signal) are considered
+    /// retryable failures.
+    #[serde(default)]
+    pub handler_permanent_failure_exit_codes: Vec<i32>,
+
+    /// Defines a key prefix under which a report is uploaded when
+    /// the handler command fails permanently. The report is placed
+    /// in the target bucket, and the prefix may contain the same
+    /// placeholders as `target_prefix`. If omitted, permanent
+    /// failures are only logged.
+    #[serde(default)]
+    pub failure_report_prefix: Option<String>,
+
+    /// Defines whether the handler command's stdout and stderr
+    /// should be captured and logged line by line, tagged with the
+    /// bucket and prefix of the batch being handled. If disabled,
+    /// the handler command inherits the event bridge's output
+    /// streams.
+    #[serde(default)]
+    pub capture_handler_output: bool,
+
+    /// Defines a key prefix under which the captured output of the
+    /// handler command is uploaded after each execution. The log is
+    /// placed in the target bucket, and the prefix may contain the
+    /// same placeholders as `target_prefix`. Setting this implies
+    /// `capture_handler_output`.
+    #[serde(default)]
+    pub handler_log_prefix: Option<String>,
+
+    /// Defines whether the captured output of the handler command
+    /// should be uploaded only when the handler command fails.
+    #[serde(default)]
+    pub handler_log_only_on_failure: bool,
+
     /// The environment variable populated with the temporary folder
     /// pulled from S3, to be passed to the handler command.
     #[serde(default = "default_root_folder_var")]
     pub root_folder_var: String,
 
+    /// The environment variable populated with a separate temporary
+    /// folder meant to hold the outputs, to be passed to the handler
+    /// command. If given, only files placed in this folder will be
+    /// uploaded to S3, and files pulled from S3 won't be compared
+    /// against their original versions. If omitted, outputs are
+    /// expected to be placed in the same folder as the inputs.
+    #[serde(default)]
+    pub output_folder_var: Option<String>,
+
     /// The environment variable populated with the bucket name from
     /// which files are pulled, to be passed to the handler command.
     #[serde(default = "default_bucket_var")]
@@ -80,6 +256,19 @@ pub struct Settings {
     /// command.
     #[serde(default = "default_key_prefix_var")]
     pub key_prefix_var: String,
+
+    /// The environment variable populated with the path to a JSON
+    /// file holding the S3 event records that triggered the
+    /// execution, to be passed to the handler command.
+    #[serde(default = "default_events_file_var")]
+    pub events_file_var: String,
+
+    /// The environment variable populated with the result of the
+    /// execution filter that caused the execution, as JSON, to be
+    /// passed to the handler command. Only populated when
+    /// `execution_filter_fan_out` is enabled.
+    #[serde(default = "default_filter_result_var")]
+    pub filter_result_var: String,
 }
 
 /// Global AWS configuration instance.
@@ -0,0 +1,108 @@
+//! Defines the errors produced while handling batches of events,
+//! classified according to whether retrying could fix them.
+
+use std::fmt;
+
+/// An error produced while handling a batch of S3 event records.
+#[derive(Debug)]
+pub enum Error {
+    /// S3 rejected a request because of throttling.
+    Throttled(anyhow::Error),
+
+    /// An object or bucket doesn't exist.
+    NotFound(anyhow::Error),
+
+    /// S3 denied access to an object or bucket.
+    AccessDenied(anyhow::Error),
+
+    /// Any other failure while communicating with S3.
+    Storage(anyhow::Error),
+
+    /// A failure while reading or writing local files.
+    LocalIo(anyhow::Error),
+
+    /// The execution filter couldn't be evaluated.
+    Filter(anyhow::Error),
+
+    /// The execution filter returned malformed directives.
+    Directives(anyhow::Error),
+
+    /// The handler command couldn't be executed.
+    HandlerSpawn(anyhow::Error),
+
+    /// The handler command didn't exit before its deadline, and had
+    /// to be terminated.
+    HandlerTimeout,
+
+    /// An unexpected failure of the event bridge itself.
+    Internal(anyhow::Error),
+}
+
+/// A result type carrying an [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the failed operation could succeed if attempted
+    /// again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Throttled(_)
+            | Error::Storage(_)
+            | Error::LocalIo(_)
+            | Error::Filter(_)
+            | Error::HandlerTimeout
+            | Error::Internal(_) => true,
+            Error::NotFound(_)
+            | Error::AccessDenied(_)
+            | Error::Directives(_)
+            | Error::HandlerSpawn(_) => false,
+        }
+    }
+
+    /// Wrap the error with additional context, keeping its
+    /// classification.
+    pub fn context<C>(self, context: C) -> Self
+    where
+        C: fmt::Display + Send + Sync + 'static,
+    {
+        match self {
+            Error::Throttled(e) => Error::Throttled(e.context(context)),
+            Error::NotFound(e) => Error::NotFound(e.context(context)),
+            Error::AccessDenied(e) => Error::AccessDenied(e.context(context)),
+            Error::Storage(e) => Error::Storage(e.context(context)),
+            Error::LocalIo(e) => Error::LocalIo(e.context(context)),
+            Error::Filter(e) => Error::Filter(e.context(context)),
+            Error::Directives(e) => Error::Directives(e.context(context)),
+            Error::HandlerSpawn(e) => Error::HandlerSpawn(e.context(context)),
+            Error::HandlerTimeout => Error::HandlerTimeout,
+            Error::Internal(e) => Error::Internal(e.context(context)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Throttled(e) => write!(f, "Throttled by S3: {:#}", e),
+            Error::NotFound(e) => write!(f, "Not found in S3: {:#}", e),
+            Error::AccessDenied(e) => write!(f, "Access denied by S3: {:#}", e),
+            Error::Storage(e) => write!(f, "S3 failure: {:#}", e),
+            Error::LocalIo(e) => write!(f, "Local file system failure: {:#}", e),
+            Error::Filter(e) => write!(f, "Execution filter failure: {:#}", e),
+            Error::Directives(e) => write!(f, "Execution filter directives failure: {:#}", e),
+            Error::HandlerSpawn(e) => write!(f, "Handler command failure: {:#}", e),
+            Error::HandlerTimeout => {
+                write!(f, "The handler command timed out and was terminated")
+            }
+            Error::Internal(e) => write!(f, "Internal failure: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(error: tokio::task::JoinError) -> Self {
+        Error::Internal(anyhow::Error::new(error).context("Failed to join a background task"))
+    }
+}
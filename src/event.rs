@@ -0,0 +1,373 @@
+//! Defines the parsing of S3 event payloads, which may be delivered
+//! either as S3 event notifications or as EventBridge events, and
+//! which may be wrapped in SNS envelopes.
+
+use anyhow::{anyhow, Context, Result};
+use aws_lambda_events::s3::{
+    S3Bucket, S3Entity, S3Event, S3EventRecord, S3Object, S3RequestParameters, S3UserIdentity,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::info;
+
+/// An S3 event as delivered by EventBridge. Reference:
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/ev-events.html
+#[derive(Deserialize)]
+struct EventBridgeEvent {
+    #[serde(rename = "detail-type")]
+    detail_type: String,
+    source: String,
+    time: DateTime<Utc>,
+    #[serde(default)]
+    region: Option<String>,
+    detail: EventBridgeDetail,
+}
+
+/// The details of an S3 event delivered by EventBridge.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct EventBridgeDetail {
+    bucket: EventBridgeBucket,
+    object: EventBridgeObject,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    deletion_type: Option<String>,
+    #[serde(default)]
+    requester: Option<String>,
+    #[serde(default)]
+    source_ip_address: Option<String>,
+}
+
+/// The bucket of an S3 event delivered by EventBridge.
+#[derive(Deserialize)]
+struct EventBridgeBucket {
+    name: String,
+}
+
+/// The object of an S3 event delivered by EventBridge.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct EventBridgeObject {
+    key: String,
+    #[serde(default)]
+    size: Option<i64>,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    version_id: Option<String>,
+    #[serde(default)]
+    sequencer: Option<String>,
+}
+
+impl EventBridgeEvent {
+    /// Determine the name the event would have as an S3 event
+    /// notification (e.g. `ObjectCreated:Put`). Event types without a
+    /// notification counterpart keep their EventBridge name.
+    fn event_name(&self) -> String {
+        match self.detail_type.as_str() {
+            "Object Created" => format!(
+                "ObjectCreated:{}",
+                match self.detail.reason.as_deref().unwrap_or_default() {
+                    "PutObject" => "Put",
+                    "POST Object" => "Post",
+                    "CopyObject" => "Copy",
+                    reason => reason,
+                }
+            ),
+            "Object Deleted" => format!(
+                "{}:{}",
+                match self.detail.reason.as_deref() {
+                    Some("Lifecycle Expiration") => "LifecycleExpiration",
+                    _ => "ObjectRemoved",
+                },
+                match self.detail.deletion_type.as_deref() {
+                    Some("Delete Marker Created") => "DeleteMarkerCreated",
+                    _ => "Delete",
+                }
+            ),
+            detail_type => detail_type.to_string(),
+        }
+    }
+
+    /// Convert the event into the record it would be as part of an
+    /// S3 event notification.
+    fn into_record(self) -> S3EventRecord {
+        let event_name = self.event_name();
+        S3EventRecord {
+            event_version: None,
+            event_source: Some(self.source),
+            aws_region: self.region,
+            event_time: self.time,
+            event_name: Some(event_name),
+            principal_id: S3UserIdentity {
+                principal_id: self.detail.requester,
+            },
+            request_parameters: S3RequestParameters {
+                source_ip_address: self.detail.source_ip_address,
+            },
+            response_elements: Default::default(),
+            s3: S3Entity {
+                schema_version: None,
+                configuration_id: None,
+                bucket: S3Bucket {
+                    name: Some(self.detail.bucket.name),
+                    ..Default::default()
+                },
+                object: S3Object {
+                    key: Some(self.detail.object.key),
+                    size: self.detail.object.size,
+                    url_decoded_key: None,
+                    version_id: self.detail.object.version_id,
+                    e_tag: self.detail.object.etag,
+                    sequencer: self.detail.object.sequencer,
+                },
+            },
+        }
+    }
+}
+
+/// Parse the S3 event records contained in the given payload,
+/// detecting whether it's an S3 event notification, an EventBridge
+/// event, or an SNS envelope holding either of them. The test event
+/// sent by S3 when notifications are configured holds no records.
+pub fn parse_records(payload: &str) -> Result<Vec<S3EventRecord>> {
+    let value = serde_json::from_str::<Value>(payload).context("Payload is not valid JSON")?;
+    parse_value(value)
+}
+
+/// Parse the S3 event records contained in the given, already
+/// deserialized, payload. See [`parse_records`].
+pub fn parse_value(value: Value) -> Result<Vec<S3EventRecord>> {
+    if let (Some("Notification"), Some(message)) = (
+        value.get("Type").and_then(Value::as_str),
+        value.get("Message").and_then(Value::as_str),
+    ) {
+        parse_records(message).context("Failed to parse the message within an SNS envelope")
+    } else if value.get("Event").and_then(Value::as_str) == Some("s3:TestEvent") {
+        info!(
+            bucket = ?value.get("Bucket").and_then(serde_json::Value::as_str),
+            "Acknowledged S3 test event"
+        );
+        Ok(Vec::new())
+    } else if value.get("Records").is_some() {
+        let event = serde_json::from_value::<S3Event>(value)
+            .context("Failed to parse payload as an S3 event notification")?;
+        Ok(event.records)
+    } else if value.get("detail-type").is_some() {
+        let event = serde_json::from_value::<EventBridgeEvent>(value)
+            .context("Failed to parse payload as an EventBridge event")?;
+        if event.source != "aws.s3" {
+            return Err(anyhow!(
+                "EventBridge event has source {:?} instead of \"aws.s3\"",
+                event.source
+            ));
+        }
+        Ok(vec![event.into_record()])
+    } else {
+        Err(anyhow!(
+            "Payload is neither an S3 event notification nor an EventBridge event"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_records;
+
+    #[test]
+    fn parse_records_reads_notifications() {
+        let records = parse_records(
+            r#"{"Records": [{
+                "eventVersion": "2.1",
+                "eventSource": "aws:s3",
+                "awsRegion": "us-east-1",
+                "eventTime": "2023-10-16T12:00:00.000Z",
+                "eventName": "ObjectCreated:Put",
+                "userIdentity": {"principalId": "EXAMPLE"},
+                "requestParameters": {"sourceIPAddress": "127.0.0.1"},
+                "responseElements": {},
+                "s3": {
+                    "s3SchemaVersion": "1.0",
+                    "configurationId": "example",
+                    "bucket": {"name": "example-bucket"},
+                    "object": {"key": "inputs/data.csv", "size": 1024}
+                }
+            }]}"#,
+        )
+        .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].event_name.as_deref(), Some("ObjectCreated:Put"));
+        assert_eq!(records[0].s3.bucket.name.as_deref(), Some("example-bucket"));
+        assert_eq!(records[0].s3.object.key.as_deref(), Some("inputs/data.csv"));
+    }
+
+    #[test]
+    fn parse_records_converts_eventbridge_events() {
+        let records = parse_records(
+            r#"{
+                "version": "0",
+                "id": "17793124-05d4-b198-2fde-7ededc63b103",
+                "detail-type": "Object Created",
+                "source": "aws.s3",
+                "account": "111122223333",
+                "time": "2023-10-16T12:00:00Z",
+                "region": "us-east-1",
+                "resources": ["arn:aws:s3:::example-bucket"],
+                "detail": {
+                    "version": "0",
+                    "bucket": {"name": "example-bucket"},
+                    "object": {
+                        "key": "inputs/data.csv",
+                        "size": 1024,
+                        "etag": "b1946ac92492d2347c6235b4d2611184",
+                        "sequencer": "00617F08299329D189"
+                    },
+                    "request-id": "N4N7GDK58NMKJ12R",
+                    "requester": "123456789012",
+                    "source-ip-address": "127.0.0.1",
+                    "reason": "PutObject"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].event_name.as_deref(), Some("ObjectCreated:Put"));
+        assert_eq!(records[0].s3.bucket.name.as_deref(), Some("example-bucket"));
+        assert_eq!(records[0].s3.object.key.as_deref(), Some("inputs/data.csv"));
+        assert_eq!(
+            records[0].s3.object.e_tag.as_deref(),
+            Some("b1946ac92492d2347c6235b4d2611184")
+        );
+    }
+
+    #[test]
+    fn parse_records_names_eventbridge_post_uploads() {
+        let records = parse_records(
+            r#"{
+                "detail-type": "Object Created",
+                "source": "aws.s3",
+                "time": "2023-10-16T12:00:00Z",
+                "detail": {
+                    "bucket": {"name": "example-bucket"},
+                    "object": {"key": "inputs/data.csv"},
+                    "reason": "POST Object"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(records[0].event_name.as_deref(), Some("ObjectCreated:Post"));
+    }
+
+    #[test]
+    fn parse_records_names_eventbridge_deletions() {
+        let records = parse_records(
+            r#"{
+                "detail-type": "Object Deleted",
+                "source": "aws.s3",
+                "time": "2023-10-16T12:00:00Z",
+                "detail": {
+                    "bucket": {"name": "example-bucket"},
+                    "object": {"key": "inputs/data.csv"},
+                    "reason": "DeleteObject",
+                    "deletion-type": "Delete Marker Created"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            records[0].event_name.as_deref(),
+            Some("ObjectRemoved:DeleteMarkerCreated")
+        );
+    }
+
+    #[test]
+    fn parse_records_names_eventbridge_expirations() {
+        let records = parse_records(
+            r#"{
+                "detail-type": "Object Deleted",
+                "source": "aws.s3",
+                "time": "2023-10-16T12:00:00Z",
+                "detail": {
+                    "bucket": {"name": "example-bucket"},
+                    "object": {"key": "inputs/data.csv"},
+                    "reason": "Lifecycle Expiration",
+                    "deletion-type": "Permanently Deleted"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            records[0].event_name.as_deref(),
+            Some("LifecycleExpiration:Delete")
+        );
+        let records = parse_records(
+            r#"{
+                "detail-type": "Object Deleted",
+                "source": "aws.s3",
+                "time": "2023-10-16T12:00:00Z",
+                "detail": {
+                    "bucket": {"name": "example-bucket"},
+                    "object": {"key": "inputs/data.csv"},
+                    "reason": "Lifecycle Expiration",
+                    "deletion-type": "Delete Marker Created"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            records[0].event_name.as_deref(),
+            Some("LifecycleExpiration:DeleteMarkerCreated")
+        );
+    }
+
+    #[test]
+    fn parse_records_unwraps_sns_envelopes() {
+        let records = parse_records(
+            r#"{
+                "Type": "Notification",
+                "MessageId": "22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324",
+                "TopicArn": "arn:aws:sns:us-east-1:111122223333:example-topic",
+                "Subject": "Amazon S3 Notification",
+                "Message": "{\"Records\":[{\"eventTime\":\"2023-10-16T12:00:00.000Z\",\"eventName\":\"ObjectCreated:Put\",\"userIdentity\":{},\"requestParameters\":{},\"s3\":{\"bucket\":{\"name\":\"example-bucket\"},\"object\":{\"key\":\"inputs/data.csv\"}}}]}",
+                "Timestamp": "2023-10-16T12:00:00.000Z"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].s3.bucket.name.as_deref(), Some("example-bucket"));
+        assert_eq!(records[0].s3.object.key.as_deref(), Some("inputs/data.csv"));
+    }
+
+    #[test]
+    fn parse_records_acknowledges_test_events() {
+        let records = parse_records(
+            r#"{
+                "Service": "Amazon S3",
+                "Event": "s3:TestEvent",
+                "Time": "2023-10-16T12:00:00.000Z",
+                "Bucket": "example-bucket",
+                "RequestId": "5582815E1AEA5ADF",
+                "HostId": "8cLeGAmw098X5cv4Zkwcmo8vvZa3eH3eKxsPzbB9wrR+YstdA6Knx4Ip8EXAMPLE"
+            }"#,
+        )
+        .unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn parse_records_rejects_other_payloads() {
+        assert!(parse_records("not json").is_err());
+        assert!(parse_records(r#"{"Service": "Amazon S3", "Event": "s3:Unknown"}"#).is_err());
+        assert!(parse_records(
+            r#"{
+                "detail-type": "EC2 Instance State-change Notification",
+                "source": "aws.ec2",
+                "time": "2023-10-16T12:00:00Z",
+                "detail": {}
+            }"#
+        )
+        .is_err());
+    }
+}
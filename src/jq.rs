@@ -1,39 +1,79 @@
-//! Provides a wrapper around jaq to operate on JSON values with jq
-//! filters.
-
-use anyhow::{anyhow, Result};
-use itertools::Itertools;
-pub use jaq_interpret::Filter;
-use jaq_interpret::{Ctx, FilterT, ParseCtx, RcIter, Val};
-use serde_json::Value;
-use tracing::warn;
-
-/// Compile a filter.
-pub fn compile(filter: &str) -> Result<Filter> {
-    let mut defs = ParseCtx::new(Vec::new());
-    defs.insert_natives(jaq_core::core());
-    defs.insert_defs(jaq_std::std());
-    let (f, errs) = jaq_parse::parse(filter, jaq_parse::main());
-    if !errs.is_empty() {
-        return Err(anyhow!(errs.into_iter().join("; ")));
-    }
-    let f = defs.compile(f.unwrap());
-    if !defs.errs.is_empty() {
-        return Err(anyhow!(defs.errs.into_iter().map(|(e, _)| e).join("; ")));
-    }
-    Ok(f)
-}
-
-/// Execute a compiled filter against an input, and produce the first
-/// serde_json value.
-pub fn first_result(filter: &Filter, input: Value) -> Option<Result<Value>> {
-    let inputs = RcIter::new(core::iter::empty());
-    let mut outputs = filter
-        .run((Ctx::new([], &inputs), Val::from(input)))
-        .map(|r| r.map(Value::from).map_err(|e| anyhow!(e.to_string())));
-    let first_result = outputs.next();
-    if outputs.next().is_some() {
-        warn!("Filter returned more than one result; subsequent results are ignored");
-    }
-    first_result
-}
+//! Provides a wrapper around jaq to operate on JSON values with jq
+//! filters.
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+pub use jaq_interpret::Filter;
+use jaq_interpret::{Ctx, FilterT, ParseCtx, RcIter, Val};
+use serde_json::Value;
+use tracing::warn;
+
+/// Compile a filter, declaring the given global variables.
+pub fn compile(filter: &str, vars: &[&str]) -> Result<Filter> {
+    let mut defs = ParseCtx::new(vars.iter().map(|var| var.to_string()).collect());
+    defs.insert_natives(jaq_core::core());
+    defs.insert_defs(jaq_std::std());
+    let (f, errs) = jaq_parse::parse(filter, jaq_parse::main());
+    if !errs.is_empty() {
+        return Err(anyhow!(errs.into_iter().join("; ")));
+    }
+    let f = defs.compile(f.unwrap());
+    if !defs.errs.is_empty() {
+        return Err(anyhow!(defs.errs.into_iter().map(|(e, _)| e).join("; ")));
+    }
+    Ok(f)
+}
+
+/// Execute a compiled filter against an input, with values for its
+/// global variables in the order they were declared, and produce the
+/// first serde_json value.
+pub fn first_result(filter: &Filter, input: Value, vars: Vec<Value>) -> Option<Result<Value>> {
+    let inputs = RcIter::new(core::iter::empty());
+    let mut outputs = filter
+        .run((
+            Ctx::new(vars.into_iter().map(Val::from), &inputs),
+            Val::from(input),
+        ))
+        .map(|r| r.map(Value::from).map_err(|e| anyhow!(e.to_string())));
+    let first_result = outputs.next();
+    if outputs.next().is_some() {
+        warn!("Filter returned more than one result; subsequent results are ignored");
+    }
+    first_result
+}
+
+/// Execute a compiled filter against an input, with values for its
+/// global variables in the order they were declared, and produce
+/// every serde_json value.
+pub fn all_results(filter: &Filter, input: Value, vars: Vec<Value>) -> Result<Vec<Value>> {
+    let inputs = RcIter::new(core::iter::empty());
+    filter
+        .run((
+            Ctx::new(vars.into_iter().map(Val::from), &inputs),
+            Val::from(input),
+        ))
+        .map(|r| r.map(Value::from).map_err(|e| anyhow!(e.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{all_results, compile, first_result};
+    use serde_json::json;
+
+    #[test]
+    fn variables_are_bound_in_declaration_order() {
+        let filter = compile("[$first, $second, .]", &["first", "second"]).unwrap();
+        let result = first_result(&filter, json!(3), vec![json!(1), json!(2)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, json!([1, 2, 3]));
+    }
+
+    #[test]
+    fn all_results_keeps_every_result() {
+        let filter = compile(".[] | select(. > $min)", &["min"]).unwrap();
+        let results = all_results(&filter, json!([1, 2, 3, 4]), vec![json!(2)]).unwrap();
+        assert_eq!(results, vec![json!(3), json!(4)]);
+    }
+}
@@ -1,5 +1,9 @@
 pub mod app;
 pub mod client;
 pub mod conf;
+pub mod error;
+pub mod event;
 mod jq;
+mod process;
 mod sign;
+pub mod sqs;
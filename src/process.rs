@@ -0,0 +1,163 @@
+//! Defines utilities for running the handler command within a
+//! deadline, optionally capturing its output.
+
+use crate::error::{self, Error};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    os::unix::process::CommandExt,
+    process::{ExitStatus, Stdio},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinSet,
+    time::{sleep_until, timeout, timeout_at, Duration, Instant},
+};
+use tracing::{info, warn, Instrument};
+
+/// Send a signal to every process in the given group. Returns
+/// whether any process was left in the group to receive it.
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) -> Result<bool> {
+    // SAFETY: killpg has no memory safety requirements; it only
+    // reads its integer arguments.
+    if unsafe { libc::killpg(pgid, signal) } == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::ESRCH) {
+        Ok(false)
+    } else {
+        Err(e)
+            .with_context(|| format!("Failed to send signal {} to process group {}", signal, pgid))
+    }
+}
+
+/// Log each line read from the given output stream of the handler
+/// command, and forward it through the given channel, if any.
+async fn forward_lines<R>(stream: R, name: &'static str, sender: Option<UnboundedSender<String>>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut segments = BufReader::new(stream).split(b'\n');
+    while let Ok(Some(segment)) = segments.next_segment().await {
+        let line = String::from_utf8_lossy(&segment).into_owned();
+        info!(stream = name, "{}", line);
+        if let Some(sender) = &sender {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Wait for the given child to exit, terminating its process group
+/// if the deadline is reached.
+async fn wait(
+    child: &mut Child,
+    deadline: Option<Instant>,
+    grace_period: Duration,
+) -> error::Result<ExitStatus> {
+    let deadline = if let Some(deadline) = deadline {
+        deadline
+    } else {
+        return child
+            .wait()
+            .await
+            .map_err(|e| Error::HandlerSpawn(e.into()));
+    };
+    // The process group outlives its leader, but the leader's ID
+    // can't be queried anymore once it's been waited for
+    let pgid = child
+        .id()
+        .ok_or_else(|| anyhow!("The handler command already exited"))
+        .and_then(|pid| libc::pid_t::try_from(pid).context("Invalid handler command process ID"))
+        .map_err(Error::Internal)?;
+    if let Ok(status) = timeout_at(deadline, child.wait()).await {
+        return status.map_err(|e| Error::HandlerSpawn(e.into()));
+    }
+
+    warn!("Handler command timed out; sending SIGTERM to its process group");
+    if let Err(e) = signal_group(pgid, libc::SIGTERM) {
+        warn!("{:?}", e);
+    }
+    let grace_deadline = Instant::now() + grace_period;
+    let exited = timeout_at(grace_deadline, child.wait()).await.is_ok();
+    // Processes left behind by the handler command get the rest of
+    // the grace period too, and are killed along with the group even
+    // if they ignore SIGTERM
+    if exited && signal_group(pgid, 0).unwrap_or_default() {
+        sleep_until(grace_deadline).await;
+    }
+    match signal_group(pgid, libc::SIGKILL) {
+        Ok(true) => warn!("Handler command left processes running after SIGTERM; sent SIGKILL"),
+        Ok(false) => (),
+        Err(e) => warn!("{:?}", e),
+    }
+    if !exited {
+        child
+            .wait()
+            .await
+            .context("Failed to wait for the killed handler command")
+            .map_err(Error::Internal)?;
+    }
+    Err(Error::HandlerTimeout)
+}
+
+/// Run the given command until it exits and return its exit
+/// status. If a deadline is given, the command is run in its own
+/// process group, which is sent a SIGTERM when the deadline is
+/// reached, and a SIGKILL once the grace period is over, to any of
+/// its processes still running. In that case
+/// [`Error::HandlerTimeout`] is returned. If `capture` is set, the
+/// command's stdout and stderr are captured and logged line by line,
+/// and if an output buffer is also given, collected into it.
+pub async fn run(
+    mut command: std::process::Command,
+    deadline: Option<Instant>,
+    grace_period: Duration,
+    capture: bool,
+    output: Option<&mut Vec<String>>,
+) -> error::Result<ExitStatus> {
+    if deadline.is_some() {
+        command.process_group(0);
+    }
+    if capture {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    let mut child = Command::from(command)
+        .spawn()
+        .map_err(|e| Error::HandlerSpawn(e.into()))?;
+
+    // Lines are only sent through a channel if they're collected
+    let (sender, receiver) = if output.is_some() {
+        let (sender, receiver) = unbounded_channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    let mut readers = JoinSet::new();
+    if let Some(stdout) = child.stdout.take() {
+        readers.spawn(forward_lines(stdout, "stdout", sender.clone()).in_current_span());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.spawn(forward_lines(stderr, "stderr", sender.clone()).in_current_span());
+    }
+    drop(sender);
+
+    let result = wait(&mut child, deadline, grace_period).await;
+
+    // The readers are drained so that the last lines of output get
+    // logged, but processes spawned by the handler command could keep
+    // the output streams open, so this is also given a deadline
+    let _ = timeout(grace_period, async {
+        while readers.join_next().await.is_some() {}
+    })
+    .await;
+    if let (Some(output), Some(mut receiver)) = (output, receiver) {
+        while let Ok(line) = receiver.try_recv() {
+            output.push(line);
+        }
+    }
+    result
+}
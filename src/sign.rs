@@ -1,6 +1,7 @@
 //! Defines utilities for comparing the state of directories in terms
 //! of the files contained within.
 
+use crate::error::{self, Error};
 use anyhow::{Context, Result};
 use base64ct::{Base64, Encoding};
 use sha1::{Digest, Sha1};
@@ -48,12 +49,12 @@ fn hash_file(path: &Path) -> Result<String> {
 
 /// Produces an empty snapshot, guaranteed to be different to anything
 /// except another empty snapshot.
-pub fn empty_signatures() -> Result<BTreeMap<PathBuf, String>> {
+pub fn empty_signatures() -> error::Result<BTreeMap<PathBuf, String>> {
     Ok(BTreeMap::new())
 }
 
 /// Produces a snapshot of the given folder.
-pub fn compute_signatures(path: &Path) -> Result<BTreeMap<PathBuf, String>> {
+pub fn compute_signatures(path: &Path) -> error::Result<BTreeMap<PathBuf, String>> {
     let mut signatures = BTreeMap::new();
     visit_dirs(path, &mut |filepath| {
         let hash = hash_file(&filepath)
@@ -61,16 +62,29 @@ pub fn compute_signatures(path: &Path) -> Result<BTreeMap<PathBuf, String>> {
         signatures.insert(filepath, hash);
         Ok(())
     })
-    .with_context(|| format!("Failed to compute signature for directory {:?}", path))?;
+    .with_context(|| format!("Failed to compute signature for directory {:?}", path))
+    .map_err(Error::LocalIo)?;
     Ok(signatures)
 }
 
+/// Produces a list of all the files within the given folder.
+pub fn list_files(path: &Path) -> error::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    visit_dirs(path, &mut |filepath| {
+        files.push(filepath);
+        Ok(())
+    })
+    .with_context(|| format!("Failed to list files in directory {:?}", path))
+    .map_err(Error::LocalIo)?;
+    Ok(files)
+}
+
 /// Produces a list of paths with differences with respect to the
 /// given signatures snapshot.
 pub fn find_signature_differences(
     path: &Path,
     snapshot: &BTreeMap<PathBuf, String>,
-) -> Result<Vec<PathBuf>> {
+) -> error::Result<Vec<PathBuf>> {
     let mut differences = Vec::new();
     visit_dirs(path, &mut |filepath| {
         let presigned = snapshot.get(&filepath);
@@ -90,6 +104,7 @@ pub fn find_signature_differences(
             "Failed to compute signature differences for directory {:?}",
             path
         )
-    })?;
+    })
+    .map_err(Error::LocalIo)?;
     Ok(differences)
 }
@@ -0,0 +1,68 @@
+//! Defines helpers shared by the consumers of SQS messages holding
+//! S3 events.
+
+use aws_sdk_sqs::{types::ChangeMessageVisibilityBatchRequestEntry, Client};
+use std::time::Duration;
+use tracing::warn;
+
+/// The maximum amount of entries accepted by SQS batch requests.
+pub const MAX_BATCH_SIZE: usize = 10;
+
+/// The maximum visibility timeout accepted by SQS, in seconds. Set to
+/// the equivalent of 12 hours.
+pub const MAX_VISIBILITY_TIMEOUT: u64 = 43200;
+
+/// Parse the approximate receive count of a message, given the value
+/// of its `ApproximateReceiveCount` attribute. Messages lacking it
+/// are considered received once.
+pub fn receive_count(attribute: Option<&str>) -> u32 {
+    attribute
+        .and_then(|receive_count| receive_count.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Change the visibility timeout of the given messages, each given
+/// as its ID, its receipt handle and its new visibility timeout (of
+/// at most [`MAX_VISIBILITY_TIMEOUT`]). Failures are only logged,
+/// since the messages become visible again anyway once their current
+/// visibility timeout expires.
+pub async fn change_visibility(
+    client: &Client,
+    queue_url: &str,
+    messages: &[(String, String, Duration)],
+) {
+    for chunk in messages.chunks(MAX_BATCH_SIZE) {
+        let result = client
+            .change_message_visibility_batch()
+            .queue_url(queue_url)
+            .set_entries(Some(
+                chunk
+                    .iter()
+                    .map(|(message_id, receipt_handle, visibility_timeout)| {
+                        ChangeMessageVisibilityBatchRequestEntry::builder()
+                            .id(message_id)
+                            .receipt_handle(receipt_handle)
+                            .visibility_timeout(
+                                visibility_timeout.as_secs().min(MAX_VISIBILITY_TIMEOUT) as i32,
+                            )
+                            .build()
+                    })
+                    .collect(),
+            ))
+            .send()
+            .await;
+        match result {
+            Err(e) => warn!(
+                "Couldn't change the visibility timeout of SQS messages: {:?}",
+                e
+            ),
+            Ok(result) if !result.failed().unwrap_or_default().is_empty() => warn!(
+                "Couldn't change the visibility timeout of some SQS messages: \
+                 {:?} out of {:?} weren't changed",
+                result.failed().unwrap_or_default().len(),
+                chunk.len()
+            ),
+            Ok(_) => (),
+        }
+    }
+}